@@ -3,6 +3,13 @@
 set -euo pipefail
 
 readonly OUTPUT_DIR="/mnt/data"
+readonly SERVER="http://${SERVER_SERVICE_HOST}:${SERVER_SERVICE_PORT}"
+readonly ITERATIONS=20
+# Split by the server into chunks-N/
+readonly CHUNK_COUNTS=(1 3 5 8 16)
+# And into formats/<container>-<codec>/
+readonly FORMATS=(tar-zstd tar-lz4 tar-gzip tar-none indexed-zstd indexed-lz4)
+readonly CONCURRENCY="1,4,8,16"
 
 export HOME="/home/main"
 
@@ -11,10 +18,20 @@ log() {
 }
 
 run_rust() {
-    log "running fs-rebuild"
-    rm -rf "${OUTPUT_DIR:?}/*"
-    time "${HOME}/fs-rebuild" --chunks 8 \
-        rebuild --host "http://${SERVER_SERVICE_HOST}:${SERVER_SERVICE_PORT}" --output "${OUTPUT_DIR}"
+    log "running fs-rebuild bench"
+    local sources=()
+    for count in "${CHUNK_COUNTS[@]}"; do
+        sources+=(--source "${SERVER}/chunks-${count}")
+    done
+    for format in "${FORMATS[@]}"; do
+        sources+=(--source "${SERVER}/formats/${format}")
+    done
+
+    "${HOME}/fs-rebuild" bench \
+        --iterations "${ITERATIONS}" \
+        --concurrency "${CONCURRENCY}" \
+        --output "${OUTPUT_DIR}/node_modules" \
+        "${sources[@]}"
 }
 
 run_shell() {
@@ -26,12 +43,13 @@ run_shell() {
 main() {
     mkdir -p "${OUTPUT_DIR}"
 
-    for _ in $(seq 20); do
+    for _ in $(seq "${ITERATIONS}"); do
         run_shell
         du -sh "${OUTPUT_DIR}"
-        run_rust
-        du -sh "${OUTPUT_DIR}"
     done
+
+    run_rust
+    du -sh "${OUTPUT_DIR}"
 }
 
 main "$@"
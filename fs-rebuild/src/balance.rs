@@ -0,0 +1,343 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error};
+
+/// Every tar entry starts with a header block and its data is padded to a
+/// whole number of blocks.
+const BLOCK_SIZE: u64 = 512;
+
+/// Bytes read from the start of a file to estimate how well it compresses.
+const SAMPLE_SIZE: u64 = 16 * 1024;
+
+/// How much each entry is assumed to add to a chunk.
+#[derive(Clone, Copy, Debug)]
+pub enum Strategy {
+    /// Entries weigh their size in the tar stream, headers and padding
+    /// included.
+    Lpt,
+    /// Entries weigh an estimate of their compressed size, so chunks full of
+    /// already compressed files don't end up much larger than the rest.
+    Compressed,
+}
+
+impl FromStr for Strategy {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Error> {
+        match value {
+            "lpt" => Ok(Strategy::Lpt),
+            "compressed" => Ok(Strategy::Compressed),
+            _ => Err(anyhow!("unknown balance strategy {:?}", value)),
+        }
+    }
+}
+
+impl Strategy {
+    pub fn weight(&self, path: &Path, size: u64, is_symlink: bool) -> io::Result<u64> {
+        if is_symlink || size == 0 {
+            return Ok(BLOCK_SIZE);
+        }
+
+        let data = match self {
+            Strategy::Lpt => round_up(size),
+            Strategy::Compressed => estimate_compressed(path, size)?,
+        };
+        Ok(BLOCK_SIZE + data)
+    }
+}
+
+/// How many chunks to balance entries over.
+#[derive(Clone, Copy, Debug)]
+pub enum Target {
+    Chunks(usize),
+    /// As many chunks as it takes for each to weigh about this much.
+    Bytes(u64),
+}
+
+/// Which entries have to stay in the same chunk.
+#[derive(Clone, Copy, Debug)]
+pub enum Grouping {
+    None,
+    /// Everything under a top level package, its own `node_modules`
+    /// included, so fetching one package only touches one chunk.
+    Package,
+}
+
+impl FromStr for Grouping {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Error> {
+        match value {
+            "none" => Ok(Grouping::None),
+            "package" => Ok(Grouping::Package),
+            _ => Err(anyhow!("unknown grouping {:?}", value)),
+        }
+    }
+}
+
+impl Grouping {
+    /// The group of an entry at `relative` to the split input, which is
+    /// treated as a `node_modules` directory. Outside of one, packages are
+    /// simply the top level directories. `None` means the entry is balanced
+    /// on its own.
+    pub fn key(&self, relative: &Path) -> Option<PathBuf> {
+        match self {
+            Grouping::None => None,
+            Grouping::Package => {
+                let mut components = relative.components();
+                let first = match components.next() {
+                    Some(Component::Normal(first)) => first,
+                    _ => return None,
+                };
+
+                let mut key = PathBuf::from(first);
+                if first.to_string_lossy().starts_with('@') {
+                    key.push(components.next()?);
+                }
+                Some(key)
+            }
+        }
+    }
+}
+
+pub struct Balance {
+    pub strategy: Strategy,
+    pub target: Target,
+    pub grouping: Grouping,
+}
+
+fn round_up(size: u64) -> u64 {
+    size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE
+}
+
+/// Compresses the start of the file at a fast level and scales the ratio up
+/// to the whole file. Only the relative weights matter, so the level chunks
+/// are actually written with doesn't have to match.
+fn estimate_compressed(path: &Path, size: u64) -> io::Result<u64> {
+    let mut sample = vec![];
+    File::open(path)?
+        .take(SAMPLE_SIZE)
+        .read_to_end(&mut sample)?;
+    if sample.is_empty() {
+        return Ok(0);
+    }
+
+    let compressed = zstd::encode_all(sample.as_slice(), 1)?;
+    let ratio = (compressed.len() as f64 / sample.len() as f64).min(1.0);
+    Ok((size as f64 * ratio).ceil() as u64)
+}
+
+/// Longest processing time first: units are placed heaviest first into the
+/// lightest chunk so far, which a min-heap of chunk loads finds in O(log k).
+///
+/// A unit is either an entry without a key or all the entries sharing one.
+/// Groups heavier than a chunk should be on average are broken back up into
+/// their entries, since keeping them whole can't be balanced.
+///
+/// Entries `pinned` to a chunk stay there, and so do new entries of a group
+/// one of them belongs to, which keeps an incremental split's assignment
+/// stable. Returns the indices of `weights` that belong to each chunk.
+pub fn assign(
+    weights: &[u64],
+    keys: &[Option<PathBuf>],
+    pinned: &[Option<usize>],
+    target: Target,
+) -> Vec<Vec<usize>> {
+    let total = weights.iter().sum::<u64>();
+    let count = match target {
+        Target::Chunks(count) => count,
+        Target::Bytes(bytes) => total.div_ceil(bytes.max(1)) as usize,
+    }
+    .max(pinned.iter().flatten().max().map_or(1, |&chunk| chunk + 1));
+    let limit = total.div_ceil(count as u64);
+
+    let mut chunks = vec![vec![]; count];
+    let mut group_chunks = BTreeMap::<&Path, usize>::new();
+    for (idx, chunk) in pinned.iter().enumerate() {
+        if let Some(chunk) = *chunk {
+            chunks[chunk].push(idx);
+            if let Some(key) = &keys[idx] {
+                group_chunks.entry(key).or_insert(chunk);
+            }
+        }
+    }
+
+    let mut units = vec![];
+    let mut groups = BTreeMap::<&Path, Vec<usize>>::new();
+    for (idx, key) in keys.iter().enumerate() {
+        if pinned[idx].is_some() {
+            continue;
+        }
+        match key {
+            Some(key) => match group_chunks.get(key.as_path()) {
+                Some(&chunk) => chunks[chunk].push(idx),
+                None => groups.entry(key).or_default().push(idx),
+            },
+            None => units.push(vec![idx]),
+        }
+    }
+    for (_, group) in groups {
+        if group.iter().map(|&idx| weights[idx]).sum::<u64>() > limit {
+            units.extend(group.into_iter().map(|idx| vec![idx]));
+        } else {
+            units.push(group);
+        }
+    }
+
+    let unit_weights = units
+        .iter()
+        .map(|unit| unit.iter().map(|&idx| weights[idx]).sum::<u64>())
+        .collect::<Vec<_>>();
+    let mut order = (0..units.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&unit| Reverse(unit_weights[unit]));
+
+    let mut loads = chunks
+        .iter()
+        .enumerate()
+        .map(|(chunk, indices)| {
+            let load = indices.iter().map(|&idx| weights[idx]).sum::<u64>();
+            Reverse((load, chunk))
+        })
+        .collect::<BinaryHeap<_>>();
+
+    for unit in order {
+        let Reverse((load, chunk)) = loads.pop().unwrap();
+        chunks[chunk].extend_from_slice(&units[unit]);
+        loads.push(Reverse((load + unit_weights[unit], chunk)));
+    }
+
+    chunks
+}
+
+/// How evenly bytes ended up spread over chunks. Rebuild time is set by the
+/// largest chunk, so `max / mean` is the number to watch.
+pub struct Report {
+    loads: Vec<u64>,
+}
+
+impl Report {
+    pub fn new(loads: Vec<u64>) -> Self {
+        Self { loads }
+    }
+
+    pub fn from_assignment(chunks: &[Vec<usize>], weights: &[u64]) -> Self {
+        Self::new(
+            chunks
+                .iter()
+                .map(|chunk| chunk.iter().map(|&idx| weights[idx]).sum())
+                .collect(),
+        )
+    }
+
+    fn imbalance(&self) -> f64 {
+        let max = self.loads.iter().max().copied().unwrap_or(0);
+        let total = self.loads.iter().sum::<u64>();
+        if total == 0 {
+            return 1.0;
+        }
+        max as f64 * self.loads.len() as f64 / total as f64
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let min = self.loads.iter().min().copied().unwrap_or(0);
+        let max = self.loads.iter().max().copied().unwrap_or(0);
+        let mean = self.loads.iter().sum::<u64>() / self.loads.len().max(1) as u64;
+        write!(
+            f,
+            "{} chunks, min {} max {} mean {} bytes, max/mean {:.3}",
+            self.loads.len(),
+            min,
+            max,
+            mean,
+            self.imbalance()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loads(chunks: &[Vec<usize>], weights: &[u64]) -> Vec<u64> {
+        chunks
+            .iter()
+            .map(|chunk| chunk.iter().map(|&idx| weights[idx]).sum())
+            .collect()
+    }
+
+    #[test]
+    fn lpt_places_heaviest_first_into_the_lightest_chunk() {
+        let weights = [7, 5, 4, 3, 3, 2];
+        let chunks = assign(&weights, &vec![None; 6], &[None; 6], Target::Chunks(3));
+
+        let mut loads = loads(&chunks, &weights);
+        loads.sort_unstable();
+        assert_eq!(loads, vec![7, 8, 9]);
+
+        let mut all = chunks.concat();
+        all.sort_unstable();
+        assert_eq!(all, (0..6).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn byte_target_sets_the_chunk_count() {
+        let weights = [1000; 10];
+        let (keys, pinned) = (vec![None; 10], [None; 10]);
+        let count = |bytes| assign(&weights, &keys, &pinned, Target::Bytes(bytes)).len();
+        assert_eq!(count(3000), 4);
+        assert_eq!(count(10000), 1);
+        assert_eq!(assign(&[], &[], &[], Target::Bytes(3000)).len(), 1);
+    }
+
+    #[test]
+    fn groups_stay_together_unless_too_heavy() {
+        let weights = [1, 1, 1, 1, 1, 1, 10, 10];
+        let key = |name: &str| Some(PathBuf::from(name));
+        let keys = [
+            key("a"),
+            key("a"),
+            key("a"),
+            key("b"),
+            key("b"),
+            None,
+            key("c"),
+            key("c"),
+        ];
+        let chunks = assign(&weights, &keys, &[None; 8], Target::Chunks(3));
+
+        let chunk_of = |idx| chunks.iter().position(|chunk| chunk.contains(&idx));
+        assert_eq!(chunk_of(0), chunk_of(1));
+        assert_eq!(chunk_of(0), chunk_of(2));
+        assert_eq!(chunk_of(3), chunk_of(4));
+        // "c" weighs more than a chunk should, so it's split up
+        assert_ne!(chunk_of(6), chunk_of(7));
+    }
+
+    #[test]
+    fn pinned_entries_and_their_groups_stay_in_place() {
+        let weights = [1, 1, 1, 100];
+        let keys = [
+            Some(PathBuf::from("a")),
+            None,
+            Some(PathBuf::from("a")),
+            None,
+        ];
+        let pinned = [Some(2), Some(2), None, None];
+        let chunks = assign(&weights, &keys, &pinned, Target::Chunks(1));
+
+        // The count grows to hold the pinned chunk
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks[2].contains(&0));
+        assert!(chunks[2].contains(&1));
+        assert!(chunks[2].contains(&2));
+        assert!(!chunks[2].contains(&3));
+    }
+}
@@ -0,0 +1,174 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Error, Result};
+use serde::Serialize;
+
+use crate::metrics::Metrics;
+use crate::rebuild::{self, RebuildOptions};
+
+#[derive(Clone, Copy, Debug)]
+pub enum Format {
+    Table,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Error> {
+        match value {
+            "table" => Ok(Format::Table),
+            "json" => Ok(Format::Json),
+            _ => Err(anyhow!("unknown bench format {:?}", value)),
+        }
+    }
+}
+
+pub struct BenchOptions {
+    pub iterations: usize,
+    pub concurrency: Vec<usize>,
+    pub format: Format,
+}
+
+/// The timings of every iteration with one source and concurrency.
+#[derive(Serialize)]
+struct Row {
+    source: String,
+    chunks: usize,
+    concurrency: usize,
+    iterations: usize,
+    min_ms: f64,
+    median_ms: f64,
+    p95_ms: f64,
+    /// What came over the network, to weigh a codec's ratio against its cost.
+    bytes_in: u64,
+    bytes_out: u64,
+    median_mib_s: f64,
+}
+
+/// Nearest rank percentile of already sorted values.
+fn percentile(sorted: &[f64], percent: f64) -> f64 {
+    let rank = (percent / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.max(1) - 1]
+}
+
+/// Empties the output so every iteration rebuilds the whole tree.
+fn clear(output: &Path) -> Result<()> {
+    match fs::remove_dir_all(output) {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error.into()),
+        _ => {}
+    }
+    fs::create_dir_all(output)?;
+    Ok(())
+}
+
+fn run(
+    output: &Path,
+    source: &str,
+    concurrency: usize,
+    iterations: usize,
+    options: &RebuildOptions,
+) -> Result<Row> {
+    let mut times = vec![];
+    let mut chunks = 0;
+    let mut bytes_in = 0;
+    let mut bytes_out = 0;
+
+    for _ in 0..iterations {
+        clear(output)?;
+        let options = RebuildOptions {
+            concurrency,
+            metrics: Arc::new(Metrics::start()),
+            ..options.clone()
+        };
+        rebuild::rebuild(output, source, &options)?;
+
+        let report = options.metrics.report();
+        times.push(report.total_ms);
+        chunks = report.chunks.len() - report.failed_attempts;
+        bytes_in = report.bytes_in;
+        bytes_out = report.bytes_out;
+    }
+
+    times.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let median_ms = percentile(&times, 50.0);
+    Ok(Row {
+        source: source.to_string(),
+        chunks,
+        concurrency,
+        iterations,
+        min_ms: times[0],
+        median_ms,
+        p95_ms: percentile(&times, 95.0),
+        bytes_in,
+        bytes_out,
+        median_mib_s: bytes_out as f64 / (1024.0 * 1024.0) / (median_ms / 1000.0),
+    })
+}
+
+fn print_table(rows: &[Row]) {
+    let width = rows.iter().map(|row| row.source.len()).max().unwrap_or(0);
+    println!(
+        "{:width$}  {:>6}  {:>11}  {:>10}  {:>10}  {:>10}  {:>8}  {:>8}",
+        "source",
+        "chunks",
+        "concurrency",
+        "min ms",
+        "median ms",
+        "p95 ms",
+        "MiB in",
+        "MiB/s",
+        width = width
+    );
+    for row in rows.iter() {
+        println!(
+            "{:width$}  {:>6}  {:>11}  {:>10.1}  {:>10.1}  {:>10.1}  {:>8.1}  {:>8.1}",
+            row.source,
+            row.chunks,
+            row.concurrency,
+            row.min_ms,
+            row.median_ms,
+            row.p95_ms,
+            row.bytes_in as f64 / (1024.0 * 1024.0),
+            row.median_mib_s,
+            width = width
+        );
+    }
+}
+
+/// Rebuilds every source at every concurrency `iterations` times into an
+/// emptied `output`, then prints the spread of the wall times. Sources are
+/// usually the same tree split into different numbers of chunks. The last
+/// rebuild is left in `output`.
+pub fn bench(
+    output: &Path,
+    sources: &[&str],
+    options: &RebuildOptions,
+    bench: &BenchOptions,
+) -> Result<()> {
+    if bench.iterations == 0 {
+        return Err(anyhow!("bench needs at least one iteration"));
+    }
+
+    let mut rows = vec![];
+    for source in sources.iter() {
+        for &concurrency in bench.concurrency.iter() {
+            let row = run(output, source, concurrency, bench.iterations, options)?;
+            eprintln!(
+                "{} at concurrency {}: median {:.1}ms",
+                source, concurrency, row.median_ms
+            );
+            rows.push(row);
+        }
+    }
+
+    match bench.format {
+        Format::Table => print_table(&rows),
+        Format::Json => println!("{}", serde_json::to_string_pretty(&rows)?),
+    }
+    Ok(())
+}
@@ -0,0 +1,212 @@
+use std::io::{self, BufRead, Read, Write};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error};
+use flate2::bufread::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use lz4_flex::frame::{FrameDecoder, FrameEncoder};
+use serde::{Deserialize, Serialize};
+
+pub const DICTIONARY_NAME: &str = "dictionary.zdict";
+
+/// Window log zstd uses for long distance matching when none is given.
+const LONG_WINDOW_LOG: u32 = 27;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct DictionaryEntry {
+    pub name: String,
+    pub sha256: String,
+}
+
+/// What chunks are compressed with. Manifests from before there was a
+/// choice are zstd.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    #[default]
+    Zstd,
+    Lz4,
+    Gzip,
+    None,
+}
+
+impl FromStr for Codec {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Error> {
+        match value {
+            "zstd" => Ok(Codec::Zstd),
+            "lz4" => Ok(Codec::Lz4),
+            "gzip" => Ok(Codec::Gzip),
+            "none" => Ok(Codec::None),
+            _ => Err(anyhow!("unknown codec {:?}", value)),
+        }
+    }
+}
+
+impl Codec {
+    /// Added to the names of files compressed as a whole.
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            Codec::Zstd => Some("zst"),
+            Codec::Lz4 => Some("lz4"),
+            Codec::Gzip => Some("gz"),
+            Codec::None => None,
+        }
+    }
+}
+
+/// The codec and its parameters chunks were written with, recorded in the
+/// manifest so rebuild can configure its decoder to match. Everything but
+/// the level is specific to zstd.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Compression {
+    #[serde(default)]
+    pub codec: Codec,
+    pub level: i32,
+    pub long_distance_matching: bool,
+    pub window_log: Option<u32>,
+    pub workers: u32,
+    pub dictionary: Option<DictionaryEntry>,
+}
+
+impl Compression {
+    pub fn effective_window_log(&self) -> Option<u32> {
+        if self.codec != Codec::Zstd {
+            return None;
+        }
+        match (self.window_log, self.long_distance_matching) {
+            (Some(window_log), _) => Some(window_log),
+            (None, true) => Some(LONG_WINDOW_LOG),
+            (None, false) => None,
+        }
+    }
+
+    /// A level of 0 uses the codec's default, lz4 has no levels.
+    pub fn encoder<W: Write>(&self, writer: W, dictionary: &[u8]) -> io::Result<Encoder<W>> {
+        match self.codec {
+            Codec::Zstd => {
+                let mut encoder = zstd::Encoder::with_dictionary(writer, self.level, dictionary)?;
+                encoder.long_distance_matching(self.long_distance_matching)?;
+                if let Some(window_log) = self.effective_window_log() {
+                    encoder.window_log(window_log)?;
+                }
+                if self.workers > 0 {
+                    encoder.multithread(self.workers)?;
+                }
+                Ok(Encoder::Zstd(encoder))
+            }
+            Codec::Lz4 => Ok(Encoder::Lz4(FrameEncoder::new(writer))),
+            Codec::Gzip => {
+                let level = match self.level {
+                    0 => flate2::Compression::default(),
+                    level @ 1..=9 => flate2::Compression::new(level as u32),
+                    level => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("gzip levels go from 1 to 9, not {}", level),
+                        ))
+                    }
+                };
+                Ok(Encoder::Gzip(GzEncoder::new(writer, level)))
+            }
+            Codec::None => Ok(Encoder::None(writer)),
+        }
+    }
+
+    /// The decoder refuses frames with a window over `max_window_log`, or
+    /// over the one chunks were written with when that's smaller, so a frame
+    /// can't make it allocate more than expected.
+    pub fn decoder<R: BufRead>(
+        &self,
+        reader: R,
+        dictionary: &[u8],
+        max_window_log: u32,
+    ) -> io::Result<Decoder<R>> {
+        match self.codec {
+            Codec::Zstd => {
+                let mut decoder = zstd::Decoder::with_dictionary(reader, dictionary)?;
+                let window_log = self
+                    .effective_window_log()
+                    .unwrap_or(max_window_log)
+                    .min(max_window_log);
+                decoder.window_log_max(window_log)?;
+                Ok(Decoder::Zstd(decoder))
+            }
+            Codec::Lz4 => Ok(Decoder::Lz4(FrameDecoder::new(reader))),
+            Codec::Gzip => Ok(Decoder::Gzip(MultiGzDecoder::new(reader))),
+            Codec::None => Ok(Decoder::None(reader)),
+        }
+    }
+}
+
+/// Compresses what's written to it with one of the codecs.
+pub enum Encoder<W: Write> {
+    Zstd(zstd::Encoder<'static, W>),
+    Lz4(FrameEncoder<W>),
+    Gzip(GzEncoder<W>),
+    None(W),
+}
+
+impl<W: Write> Encoder<W> {
+    /// Ends the compressed stream and returns the inner writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Zstd(encoder) => encoder.finish(),
+            Encoder::Lz4(encoder) => Ok(encoder.finish()?),
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::None(writer) => Ok(writer),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Zstd(encoder) => encoder.write(buf),
+            Encoder::Lz4(encoder) => encoder.write(buf),
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::None(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Zstd(encoder) => encoder.flush(),
+            Encoder::Lz4(encoder) => encoder.flush(),
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::None(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Decompresses what's read through it with one of the codecs.
+pub enum Decoder<R: BufRead> {
+    Zstd(zstd::Decoder<'static, R>),
+    Lz4(FrameDecoder<R>),
+    Gzip(MultiGzDecoder<R>),
+    None(R),
+}
+
+impl<R: BufRead> Decoder<R> {
+    /// Returns the inner reader, with whatever the decoder didn't need.
+    pub fn finish(self) -> R {
+        match self {
+            Decoder::Zstd(decoder) => decoder.finish(),
+            Decoder::Lz4(decoder) => decoder.into_inner(),
+            Decoder::Gzip(decoder) => decoder.into_inner(),
+            Decoder::None(reader) => reader,
+        }
+    }
+}
+
+impl<R: BufRead> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Decoder::Zstd(decoder) => decoder.read(buf),
+            Decoder::Lz4(decoder) => decoder.read(buf),
+            Decoder::Gzip(decoder) => decoder.read(buf),
+            Decoder::None(reader) => reader.read(buf),
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use std::fs::{self, File, Permissions};
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error};
+
+/// What a rebuild makes durable before it's done.
+#[derive(Clone, Copy, Debug)]
+pub enum Durability {
+    /// Nothing, the page cache is written back whenever the kernel decides.
+    None,
+    /// Every file is fsynced once written.
+    File,
+    /// The whole filesystem is synced once at the end.
+    Syncfs,
+    /// Every file is fsynced and then every directory, children first, so
+    /// the entries pointing at the files are durable too.
+    Dir,
+}
+
+impl FromStr for Durability {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Error> {
+        match value {
+            "none" => Ok(Durability::None),
+            "file" => Ok(Durability::File),
+            "syncfs" => Ok(Durability::Syncfs),
+            "dir" => Ok(Durability::Dir),
+            _ => Err(anyhow!("unknown durability {:?}", value)),
+        }
+    }
+}
+
+impl Durability {
+    /// Whether every file is fsynced as soon as it's written.
+    pub fn per_file(self) -> bool {
+        matches!(self, Durability::File | Durability::Dir)
+    }
+
+    /// Whether the rename swapping a staged tree into place is made durable,
+    /// by fsyncing the directory holding it.
+    pub fn syncs_swap(self) -> bool {
+        matches!(self, Durability::Syncfs | Durability::Dir)
+    }
+
+    /// Makes what the rebuild wrote under `output` durable, once it's all
+    /// there. `directories` are relative to it, parents first.
+    pub fn finish<'a, I>(self, output: &Path, directories: I) -> io::Result<()>
+    where
+        I: DoubleEndedIterator<Item = &'a Path>,
+    {
+        match self {
+            Durability::None | Durability::File => Ok(()),
+            Durability::Syncfs => syncfs(output),
+            Durability::Dir => {
+                for directory in directories.rev() {
+                    sync_path(&output.join(directory))?;
+                }
+                sync_path(output)
+            }
+        }
+    }
+}
+
+/// Opens a file or directory the rebuild restored, read only. Unless running
+/// as root, that fails for one its owner can't read, like a file restored
+/// with mode 0o200, so it's made readable just long enough to be opened.
+pub fn open_restored(path: &Path) -> io::Result<File> {
+    let error = match File::open(path) {
+        Ok(file) => return Ok(file),
+        Err(error) => error,
+    };
+    let mode = fs::symlink_metadata(path)?.permissions().mode() & 0o7777;
+    if error.kind() != io::ErrorKind::PermissionDenied || mode & 0o400 != 0 {
+        return Err(error);
+    }
+
+    fs::set_permissions(path, Permissions::from_mode(mode | 0o400))?;
+    let file = File::open(path);
+    fs::set_permissions(path, Permissions::from_mode(mode))?;
+    file
+}
+
+/// Fsyncs a file or directory by path, for writers that don't keep a handle
+/// to what they wrote. A mode `open_restored` had to set back isn't synced.
+pub fn sync_path(path: &Path) -> io::Result<()> {
+    open_restored(path)?.sync_all()
+}
+
+fn syncfs(path: &Path) -> io::Result<()> {
+    let file = File::open(path)?;
+    if unsafe { libc::syncfs(file.as_raw_fd()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
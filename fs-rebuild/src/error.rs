@@ -0,0 +1,77 @@
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum RebuildError {
+    #[error("chunk {name} is {actual} bytes, expected {expected}")]
+    ChunkSize {
+        name: String,
+        expected: u64,
+        actual: u64,
+    },
+
+    #[error("chunk {name} has sha256 {actual}, expected {expected}")]
+    ChunkChecksum {
+        name: String,
+        expected: String,
+        actual: String,
+    },
+
+    #[error("dictionary {name} has sha256 {actual}, expected {expected}")]
+    DictionaryChecksum {
+        name: String,
+        expected: String,
+        actual: String,
+    },
+
+    #[error("file {path:?} is missing after unpacking")]
+    MissingFile { path: PathBuf },
+
+    #[error("file {path:?} isn't in the manifest")]
+    NotInManifest { path: PathBuf },
+
+    #[error("chunk {name} has no valid table of contents")]
+    CorruptIndex { name: String },
+
+    #[error("file {path:?} has sha256 {actual}, expected {expected}")]
+    CorruptFile {
+        path: PathBuf,
+        expected: String,
+        actual: String,
+    },
+
+    #[error("symlink {path:?} points to {actual:?}, expected {expected:?}")]
+    LinkTarget {
+        path: PathBuf,
+        expected: PathBuf,
+        actual: PathBuf,
+    },
+
+    #[error("refusing {path:?}, it isn't a relative path inside the output")]
+    UnsafePath { path: PathBuf },
+
+    #[error("refusing symlink {path:?} to {target:?}, it points outside the output")]
+    LinkEscapes { path: PathBuf, target: PathBuf },
+
+    #[error("refusing {kind} entry {path:?}")]
+    UnsupportedEntry { path: PathBuf, kind: String },
+
+    #[error("refusing to unpack more than {limit} bytes")]
+    TooManyBytes { limit: u64 },
+
+    #[error("refusing to unpack more than {limit} entries")]
+    TooManyEntries { limit: u64 },
+
+    #[error("refusing a zstd window log of {window_log}, the limit is {limit}")]
+    WindowTooLarge { window_log: u32, limit: u32 },
+
+    #[error("{name} has no valid signature")]
+    MissingSignature { name: String },
+
+    #[error("{name} isn't signed by the public key")]
+    BadSignature { name: String },
+
+    #[error("failed after all retries: {}", names.join(", "))]
+    ChunksFailed { names: Vec<String> },
+}
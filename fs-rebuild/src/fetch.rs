@@ -0,0 +1,195 @@
+use std::io::{self, Read};
+use std::thread;
+use std::time::Duration;
+
+use reqwest::blocking::{Client, Response};
+use reqwest::header::RANGE;
+use reqwest::StatusCode;
+
+#[derive(Clone)]
+pub struct Fetcher {
+    client: Client,
+    retries: u32,
+    backoff: Duration,
+}
+
+impl Fetcher {
+    pub fn new(retries: u32, backoff: Duration) -> Self {
+        Self {
+            client: Client::new(),
+            retries,
+            backoff,
+        }
+    }
+
+    /// Opens a streaming body for `url`, see `Body` for how failures are retried.
+    pub fn open(&self, url: &str) -> io::Result<Body> {
+        let mut body = Body {
+            fetcher: self.clone(),
+            url: url.to_string(),
+            response: None,
+            offset: 0,
+            attempt: 0,
+        };
+        body.connect()?;
+        Ok(body)
+    }
+
+    /// Reads `len` bytes of `url` starting at `offset`, retrying transient
+    /// failures like `Body` does.
+    pub fn read_range(&self, url: &str, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        let mut attempt = 0;
+        loop {
+            match self.request_range(url, offset, len) {
+                Ok(bytes) => return Ok(bytes),
+                Err(error) if attempt < self.retries && is_transient(&error) => {
+                    let delay = self.delay(attempt);
+                    attempt += 1;
+                    eprintln!(
+                        "retrying {} bytes {}+{} in {:?} ({})",
+                        url, offset, len, delay, error
+                    );
+                    thread::sleep(delay);
+                }
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    /// How long to wait before retry `attempt`, doubling each time. Saturates
+    /// rather than overflowing for large `--retries`.
+    fn delay(&self, attempt: u32) -> Duration {
+        self.backoff.saturating_mul(2u32.saturating_pow(attempt))
+    }
+
+    fn request_range(&self, url: &str, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        if len == 0 {
+            return Ok(vec![]);
+        }
+
+        let mut response = self
+            .client
+            .get(url)
+            .header(RANGE, format!("bytes={}-{}", offset, offset + len - 1))
+            .send()
+            .and_then(Response::error_for_status)
+            .map_err(io::Error::other)?;
+
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            // The server ignored the range, skip to it in the whole body
+            io::copy(&mut (&mut response).take(offset), &mut io::sink())?;
+        }
+
+        // `len` comes from the index, so it isn't trusted to preallocate
+        let mut bytes = vec![];
+        response.take(len).read_to_end(&mut bytes)?;
+        if (bytes.len() as u64) < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(bytes)
+    }
+
+    fn request(&self, url: &str, offset: u64) -> io::Result<Response> {
+        let mut request = self.client.get(url);
+        if offset > 0 {
+            request = request.header(RANGE, format!("bytes={}-", offset));
+        }
+
+        let mut response = request
+            .send()
+            .and_then(Response::error_for_status)
+            .map_err(io::Error::other)?;
+
+        if offset > 0 && response.status() != StatusCode::PARTIAL_CONTENT {
+            // The server ignored the range, skip past what was already read
+            let skipped = io::copy(&mut (&mut response).take(offset), &mut io::sink())?;
+            if skipped < offset {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+
+        Ok(response)
+    }
+}
+
+/// A response body that survives transient failures. When a request or a
+/// read fails, it waits with exponential backoff and reconnects with a
+/// `Range` header starting at the first byte it has not returned yet.
+pub struct Body {
+    fetcher: Fetcher,
+    url: String,
+    response: Option<Response>,
+    offset: u64,
+    attempt: u32,
+}
+
+impl Body {
+    fn connect(&mut self) -> io::Result<()> {
+        loop {
+            match self.fetcher.request(&self.url, self.offset) {
+                Ok(response) => {
+                    self.response = Some(response);
+                    return Ok(());
+                }
+                Err(error) => self.backoff(error)?,
+            }
+        }
+    }
+
+    fn backoff(&mut self, error: io::Error) -> io::Result<()> {
+        if self.attempt >= self.fetcher.retries || !is_transient(&error) {
+            return Err(error);
+        }
+
+        let delay = self.fetcher.delay(self.attempt);
+        self.attempt += 1;
+        eprintln!(
+            "retrying {} from byte {} in {:?} ({})",
+            self.url, self.offset, delay, error
+        );
+        thread::sleep(delay);
+        Ok(())
+    }
+}
+
+impl Read for Body {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let response = match self.response.as_mut() {
+                Some(response) => response,
+                None => {
+                    self.connect()?;
+                    continue;
+                }
+            };
+
+            match response.read(buf) {
+                Ok(read) => {
+                    self.offset += read as u64;
+                    self.attempt = 0;
+                    return Ok(read);
+                }
+                Err(error) => {
+                    self.response = None;
+                    self.backoff(error)?;
+                }
+            }
+        }
+    }
+}
+
+fn is_transient(error: &io::Error) -> bool {
+    let status = error
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<reqwest::Error>())
+        .and_then(reqwest::Error::status);
+
+    match status {
+        Some(status) => {
+            status.is_server_error()
+                || status == StatusCode::REQUEST_TIMEOUT
+                || status == StatusCode::TOO_MANY_REQUESTS
+        }
+        None => true,
+    }
+}
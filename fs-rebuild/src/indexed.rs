@@ -0,0 +1,367 @@
+use std::convert::TryInto;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{symlink, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use ed25519_dalek::PublicKey;
+use serde::{Deserialize, Serialize};
+
+use crate::compression::Compression;
+use crate::error::RebuildError;
+use crate::fetch::Fetcher;
+use crate::hash::sha256_hex;
+use crate::manifest::{ChunkEntry, Container, Manifest};
+use crate::pool::Metadata;
+use crate::rebuild::{self, Preserve};
+use crate::safety::{self, Limits};
+use crate::source::{self, ChunkSource};
+
+// An indexed chunk is every entry's header and data, one after the other so
+// the chunk can be unpacked as it streams in, then a table of contents and a
+// fixed size trailer pointing at it, so one file can be read with a few
+// ranged reads:
+//
+//   entry   = u32 header length, JSON `IndexEntry`, compressed data
+//   chunk   = entry*, u32 0, JSON [`TocEntry`], trailer
+//   trailer = u64 table of contents offset, u64 its length, `MAGIC`
+//
+// Integers are little endian. Each file is compressed on its own, which
+// compresses worse than a whole tar stream unless there's a dictionary.
+
+const MAGIC: &[u8; 8] = b"FSRBIDX1";
+const TRAILER_LEN: u64 = 24;
+
+/// Entry headers are a path and a few numbers, anything longer is corrupt.
+const MAX_HEADER_LEN: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    File,
+    Symlink,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct IndexEntry {
+    pub path: PathBuf,
+    pub kind: Kind,
+    pub mode: u32,
+    pub mtime: u64,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    pub compressed_size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<PathBuf>,
+}
+
+impl IndexEntry {
+    fn new(path: &Path, kind: Kind, meta: &fs::Metadata) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            kind,
+            mode: meta.mode(),
+            mtime: meta.mtime().max(0) as u64,
+            uid: meta.uid(),
+            gid: meta.gid(),
+            size: 0,
+            compressed_size: 0,
+            target: None,
+        }
+    }
+
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            mode: self.mode,
+            mtime: self.mtime,
+            uid: self.uid as u64,
+            gid: self.gid as u64,
+        }
+    }
+}
+
+/// An entry in the table of contents, with the offset of its data.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TocEntry {
+    #[serde(flatten)]
+    pub entry: IndexEntry,
+    pub offset: u64,
+}
+
+/// Writes an indexed chunk, compressing each file on its own.
+pub struct IndexWriter<'a, W: Write> {
+    inner: W,
+    compression: &'a Compression,
+    dictionary: &'a [u8],
+    offset: u64,
+    toc: Vec<TocEntry>,
+}
+
+impl<'a, W: Write> IndexWriter<'a, W> {
+    pub fn new(inner: W, compression: &'a Compression, dictionary: &'a [u8]) -> Self {
+        Self {
+            inner,
+            compression,
+            dictionary,
+            offset: 0,
+            toc: vec![],
+        }
+    }
+
+    fn append(&mut self, entry: IndexEntry, data: &[u8]) -> io::Result<()> {
+        let header = serde_json::to_vec(&entry)?;
+        self.inner.write_all(&(header.len() as u32).to_le_bytes())?;
+        self.inner.write_all(&header)?;
+        self.offset += 4 + header.len() as u64;
+
+        self.toc.push(TocEntry {
+            entry,
+            offset: self.offset,
+        });
+        self.inner.write_all(data)?;
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+
+    /// The file is compressed in memory first, since its header holds the
+    /// compressed size.
+    pub fn append_file<R: Read>(
+        &mut self,
+        path: &Path,
+        meta: &fs::Metadata,
+        reader: &mut R,
+    ) -> io::Result<()> {
+        let mut encoder = self.compression.encoder(vec![], self.dictionary)?;
+        let size = io::copy(reader, &mut encoder)?;
+        let data = encoder.finish()?;
+
+        let mut entry = IndexEntry::new(path, Kind::File, meta);
+        entry.size = size;
+        entry.compressed_size = data.len() as u64;
+        self.append(entry, &data)
+    }
+
+    pub fn append_link(
+        &mut self,
+        path: &Path,
+        meta: &fs::Metadata,
+        target: &Path,
+    ) -> io::Result<()> {
+        let mut entry = IndexEntry::new(path, Kind::Symlink, meta);
+        entry.target = Some(target.to_path_buf());
+        self.append(entry, &[])
+    }
+
+    /// Ends the entries, writes the table of contents and returns the inner
+    /// writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.inner.write_all(&0u32.to_le_bytes())?;
+        let toc = serde_json::to_vec(&self.toc)?;
+        self.inner.write_all(&toc)?;
+
+        self.inner.write_all(&(self.offset + 4).to_le_bytes())?;
+        self.inner.write_all(&(toc.len() as u64).to_le_bytes())?;
+        self.inner.write_all(MAGIC)?;
+        Ok(self.inner)
+    }
+}
+
+/// Reads the entries of an indexed chunk in order, as it streams in.
+pub struct IndexReader<R> {
+    inner: R,
+}
+
+impl<R: Read> IndexReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner }
+    }
+
+    /// Returns the next entry, or `None` where the table of contents starts.
+    /// The entry's data has to be read through `data` before the next one.
+    pub fn next_entry(&mut self) -> io::Result<Option<IndexEntry>> {
+        let mut len = [0; 4];
+        self.inner.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len) as usize;
+        if len == 0 {
+            return Ok(None);
+        }
+        if len > MAX_HEADER_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("refusing an entry header of {} bytes", len),
+            ));
+        }
+
+        let mut header = vec![0; len];
+        self.inner.read_exact(&mut header)?;
+        Ok(Some(serde_json::from_slice(&header)?))
+    }
+
+    /// The compressed data of `entry`.
+    pub fn data(&mut self, entry: &IndexEntry) -> io::Take<&mut R> {
+        (&mut self.inner).take(entry.compressed_size)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+/// Creates a file or symlink the way tar does, replacing whatever is there
+/// and refusing to write through a symlink leading out of the output. The
+/// directories holding it must already exist. Returns the time spent on
+/// fsync.
+pub fn unpack_entry<R: Read>(
+    output: &Path,
+    entry: &IndexEntry,
+    data: &mut R,
+    preserve: Preserve,
+    sync: bool,
+) -> io::Result<Duration> {
+    let path = output.join(&entry.path);
+    safety::check_beneath(output, &path)?;
+
+    // Creating with O_EXCL never follows a symlink left in the way
+    let create = || match &entry.target {
+        Some(target) => symlink(target, &path).map(|()| None),
+        None => OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .map(Some),
+    };
+    let file = match create() {
+        Err(error) if error.kind() == io::ErrorKind::AlreadyExists => {
+            fs::remove_file(&path)?;
+            create()?
+        }
+        result => result?,
+    };
+
+    let mut file = match file {
+        Some(file) => file,
+        None => return Ok(Duration::ZERO),
+    };
+    io::copy(data, &mut file)?;
+    entry.metadata().restore(&file, preserve)?;
+
+    if !sync {
+        return Ok(Duration::ZERO);
+    }
+    let started = Instant::now();
+    file.sync_all()?;
+    Ok(started.elapsed())
+}
+
+/// Reads the table of contents at the end of an indexed chunk, without
+/// fetching the rest of it.
+pub fn read_toc(source: &dyn ChunkSource, chunk: &ChunkEntry) -> Result<Vec<TocEntry>> {
+    let corrupt = || RebuildError::CorruptIndex {
+        name: chunk.name.clone(),
+    };
+    if chunk.size < TRAILER_LEN {
+        return Err(corrupt().into());
+    }
+
+    let trailer = source.read_range(&chunk.name, chunk.size - TRAILER_LEN, TRAILER_LEN)?;
+    if trailer[16..] != MAGIC[..] {
+        return Err(corrupt().into());
+    }
+    let offset = u64::from_le_bytes(trailer[..8].try_into().unwrap());
+    let len = u64::from_le_bytes(trailer[8..16].try_into().unwrap());
+    if offset.checked_add(len) != Some(chunk.size - TRAILER_LEN) {
+        return Err(corrupt().into());
+    }
+
+    let toc = source.read_range(&chunk.name, offset, len)?;
+    Ok(serde_json::from_slice(&toc)?)
+}
+
+/// Reads one file out of an indexed chunk through its table of contents and
+/// checks it against the manifest.
+pub fn read_file(
+    source: &dyn ChunkSource,
+    manifest: &Manifest,
+    dictionary: &[u8],
+    max_window_log: u32,
+    path: &Path,
+) -> Result<Vec<u8>> {
+    if manifest.container != Container::Indexed {
+        return Err(anyhow!(
+            "reading a single file needs chunks split with --container indexed"
+        ));
+    }
+
+    let (chunk, file) = manifest
+        .chunks
+        .iter()
+        .find_map(|chunk| {
+            let file = chunk.files.iter().find(|file| file.path == path)?;
+            Some((chunk, file))
+        })
+        .ok_or_else(|| RebuildError::NotInManifest {
+            path: path.to_path_buf(),
+        })?;
+
+    let toc = read_toc(source, chunk)?;
+    let entry = toc
+        .iter()
+        .find(|toc| toc.entry.kind == Kind::File && toc.entry.path == path)
+        .ok_or_else(|| RebuildError::CorruptIndex {
+            name: chunk.name.clone(),
+        })?;
+
+    let compressed = source.read_range(&chunk.name, entry.offset, entry.entry.compressed_size)?;
+    let decoder = manifest
+        .compression
+        .decoder(&compressed[..], dictionary, max_window_log)?;
+    // Grows with what's actually decoded rather than trusting the size
+    let mut data = vec![];
+    decoder.take(file.size).read_to_end(&mut data)?;
+
+    let sha256 = sha256_hex(&data);
+    if sha256 != file.sha256 {
+        return Err(RebuildError::CorruptFile {
+            path: path.to_path_buf(),
+            expected: file.sha256.clone(),
+            actual: sha256,
+        }
+        .into());
+    }
+    Ok(data)
+}
+
+/// What `cat` needs to fetch one file and trust it.
+pub struct CatOptions {
+    pub retries: u32,
+    pub backoff: Duration,
+    pub max_window_log: u32,
+    pub public_key: Option<PublicKey>,
+}
+
+/// Reads the file at `path` from the indexed chunks at `location`.
+pub fn cat(location: &str, path: &Path, options: &CatOptions) -> Result<Vec<u8>> {
+    let fetcher = Fetcher::new(options.retries, options.backoff);
+    let source = source::from_location(location, fetcher);
+    let manifest = rebuild::fetch_manifest(source.as_ref(), options.public_key.as_ref())?;
+    // Nothing is unpacked, so only the window is limited
+    let limits = Limits {
+        max_bytes: u64::MAX,
+        max_entries: u64::MAX,
+        max_window_log: options.max_window_log,
+    };
+    safety::check_manifest(&manifest, &limits)?;
+    let dictionary = rebuild::fetch_dictionary(source.as_ref(), &manifest.compression)?;
+
+    read_file(
+        source.as_ref(),
+        &manifest,
+        &dictionary,
+        options.max_window_log,
+        path,
+    )
+}
@@ -1,169 +1,185 @@
-use std::cmp::Ordering;
-use std::collections::BinaryHeap;
-use std::fs;
+mod balance;
+mod bench;
+mod compression;
+mod durability;
+mod error;
+mod fetch;
+mod hash;
+mod indexed;
+mod manifest;
+mod metrics;
+mod pool;
+mod rebuild;
+mod safety;
+mod serve;
+mod signing;
+mod source;
+mod split;
+mod staging;
+mod store;
+#[cfg(feature = "io-uring")]
+mod uring;
+
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
-use std::thread;
-
-use anyhow::Result;
-use bytes::Buf;
-use clap::{App, AppSettings, Arg, SubCommand};
-use tar::{Archive, Builder};
-use walkdir::WalkDir;
-use zstd::stream::read::Decoder;
-use zstd::stream::write::Encoder;
-
-fn fetch_chunk(output: PathBuf, host: String, idx: usize) -> Result<()> {
-    let resp = reqwest::blocking::get(&format!("{}/{}.tar.zst", host, idx))?;
-
-    let bytes = resp.bytes()?;
-    let decoder = Decoder::new(bytes.reader())?;
-
-    let mut archive = Archive::new(decoder);
-    archive.unpack(output)?;
-
-    Ok(())
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use ed25519_dalek::PublicKey;
+
+use crate::balance::{Balance, Target};
+use crate::compression::Compression;
+use crate::metrics::Metrics;
+
+/// Arguments for fetching a manifest and trusting it, shared by every
+/// subcommand that reads chunks.
+fn source_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("retries")
+            .long("retries")
+            .default_value("3")
+            .takes_value(true)
+            .help("retries per request for transient failures"),
+        Arg::with_name("backoff-ms")
+            .long("backoff-ms")
+            .default_value("200")
+            .takes_value(true)
+            .help("initial delay between retries, doubled after each one"),
+        Arg::with_name("max-window-log")
+            .long("max-window-log")
+            .default_value("27")
+            .takes_value(true)
+            .help("refuse zstd windows larger than 2^N bytes"),
+        Arg::with_name("public-key")
+            .long("public-key")
+            .takes_value(true)
+            .help("refuse unsigned manifests; in place, a bad chunk is written first"),
+    ]
 }
 
-fn rebuild(output: &Path, host: &str, count: usize) -> Result<()> {
-    let mut threads = vec![];
-
-    for idx in 1..(count + 1) {
-        let host = host.to_string();
-        let output = output.to_path_buf();
-        threads.push(thread::spawn(move || fetch_chunk(output, host, idx)));
-    }
-
-    for handle in threads {
-        if let Err(error) = handle.join().unwrap() {
-            return Err(error);
-        }
-    }
-
-    Ok(())
-}
-
-#[derive(Clone, Debug, Eq, PartialEq)]
-struct FileMeta {
-    path: PathBuf,
-    size: u64,
-}
-
-impl FileMeta {
-    fn new(path: PathBuf, size: u64) -> Self {
-        Self { path, size }
-    }
-}
-
-impl Ord for FileMeta {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.size.cmp(&other.size)
-    }
+/// Arguments shared by rebuild and bench, which rebuilds over and over.
+fn rebuild_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    let mut args = vec![
+        Arg::with_name("preserve-permissions")
+            .long("preserve-permissions")
+            .help("also restore setuid, setgid and sticky bits"),
+        Arg::with_name("preserve-ownership")
+            .long("preserve-ownership")
+            .help("restore file owners and groups, usually needs root"),
+        Arg::with_name("no-mtime")
+            .long("no-mtime")
+            .help("don't restore modification times"),
+        Arg::with_name("refetch-rounds")
+            .long("refetch-rounds")
+            .default_value("0")
+            .takes_value(true)
+            .help("times to re-fetch only the chunks that failed"),
+        Arg::with_name("max-bytes")
+            .long("max-bytes")
+            .default_value("68719476736")
+            .takes_value(true)
+            .help("refuse to unpack more than this many bytes in total"),
+        Arg::with_name("max-entries")
+            .long("max-entries")
+            .default_value("10000000")
+            .takes_value(true)
+            .help("refuse to unpack more than this many entries in total"),
+        Arg::with_name("writer")
+            .long("writer")
+            .default_value("tar")
+            .possible_values(&["tar", "pool", "io-uring"])
+            .takes_value(true)
+            .help("pool writes from --write-workers, io-uring needs its feature"),
+        Arg::with_name("write-workers")
+            .long("write-workers")
+            .default_value("4")
+            .takes_value(true)
+            .help("threads writing the files of each chunk with --writer pool"),
+        Arg::with_name("durability")
+            .long("durability")
+            .default_value("none")
+            .possible_values(&["none", "file", "syncfs", "dir"])
+            .takes_value(true)
+            .help("fsync each file, syncfs at the end, or fsync files and dirs"),
+    ];
+    args.extend(source_args());
+    args
 }
 
-impl PartialOrd for FileMeta {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.size.cmp(&other.size))
-    }
-}
-
-struct OutputChunk(Vec<FileMeta>);
-
-impl OutputChunk {
-    fn size(&self) -> u64 {
-        self.0.iter().map(|entry| entry.size).sum()
-    }
-
-    fn write(&self, prefix: &Path, output: &Path, idx: usize) -> Result<()> {
-        let tar_file = fs::File::create(output.join(&format!("{}.tar", idx)))?;
-        let compressed = Encoder::new(tar_file, 0)?;
-        let mut archive = Builder::new(compressed);
-
-        println!("writing to {}", &format!("{}.tar.zst", idx));
-        println!("writing {} chunks", self.0.len());
-
-        for meta in self.0.iter() {
-            let mut file = fs::File::open(&meta.path)?;
-            let path = meta.path.strip_prefix(prefix)?;
-            archive.append_file(path, &mut file)?;
-        }
-        Ok(())
-    }
-}
-
-struct OutputChunks {
-    prefix: PathBuf,
-    chunks: Vec<OutputChunk>,
-}
-
-impl OutputChunks {
-    fn new(prefix: PathBuf, count: usize) -> Self {
-        let mut chunks = Vec::new();
-        for _ in 0..count {
-            chunks.push(OutputChunk(vec![]))
-        }
-        OutputChunks { prefix, chunks }
-    }
-
-    fn push(&mut self, meta: FileMeta) {
-        let mut min_index = 0;
-        let mut min_value = u64::MAX;
-
-        for (idx, chunk) in self.chunks.iter().enumerate() {
-            let size = chunk.size();
-            if size < min_value {
-                min_index = idx;
-                min_value = size
-            }
-        }
-
-        self.chunks[min_index].0.push(meta)
-    }
-
-    fn write(&self, output: &Path) -> Result<()> {
-        for (idx, chunk) in self.chunks.iter().enumerate() {
-            chunk.write(&self.prefix, output, idx + 1)?;
-        }
-        Ok(())
-    }
+fn public_key(matches: &ArgMatches) -> Result<Option<PublicKey>> {
+    matches
+        .value_of("public-key")
+        .map(|path| signing::read_public_key(Path::new(path)))
+        .transpose()
 }
 
-fn build_output_chunks(input: &Path, count: usize) -> Result<OutputChunks> {
-    let mut meta_heap = BinaryHeap::new();
-
-    println!("reading from {:?}", input);
-    for entry_result in WalkDir::new(input) {
-        let entry = entry_result?;
-        let meta = entry.metadata()?;
-        if meta.is_file() {
-            meta_heap.push(FileMeta::new(entry.path().to_path_buf(), meta.len()))
-        }
+/// No chunk would ever be fetched with a concurrency of 0.
+fn parse_concurrency(value: &str) -> Result<usize> {
+    match value.parse()? {
+        0 => Err(anyhow!("--concurrency has to be at least 1")),
+        concurrency => Ok(concurrency),
     }
-
-    let mut chunks = OutputChunks::new(input.to_path_buf(), count);
-    for meta in meta_heap {
-        chunks.push(meta)
-    }
-
-    Ok(chunks)
 }
 
-fn split(input: &Path, output: &Path, count: usize) -> Result<()> {
-    let chunks = build_output_chunks(input, count)?;
-    chunks.write(output)
+fn rebuild_options(matches: &ArgMatches, concurrency: usize) -> Result<rebuild::RebuildOptions> {
+    Ok(rebuild::RebuildOptions {
+        retries: matches.value_of("retries").unwrap().parse()?,
+        backoff: Duration::from_millis(matches.value_of("backoff-ms").unwrap().parse()?),
+        refetch_rounds: matches.value_of("refetch-rounds").unwrap().parse()?,
+        concurrency,
+        preserve: rebuild::Preserve {
+            permissions: matches.is_present("preserve-permissions"),
+            mtime: !matches.is_present("no-mtime"),
+            ownership: matches.is_present("preserve-ownership"),
+        },
+        delta: matches.is_present("delta"),
+        limits: safety::Limits {
+            max_bytes: matches.value_of("max-bytes").unwrap().parse()?,
+            max_entries: matches.value_of("max-entries").unwrap().parse()?,
+            max_window_log: matches.value_of("max-window-log").unwrap().parse()?,
+        },
+        public_key: public_key(matches)?,
+        metrics: Arc::new(Metrics::start()),
+        writer: matches.value_of("writer").unwrap().parse()?,
+        write_workers: matches.value_of("write-workers").unwrap().parse()?,
+        durability: matches.value_of("durability").unwrap().parse()?,
+    })
 }
 
 fn main() -> Result<()> {
     let matches = App::new("fs-rebuild")
-        .arg(
-            Arg::with_name("chunks")
-                .short("c")
-                .long("chunks")
-                .default_value("4")
-                .takes_value(true),
-        )
         .subcommand(
             SubCommand::with_name("split")
                 .about("split directory into chunks")
+                .arg(
+                    Arg::with_name("chunks")
+                        .short("c")
+                        .long("chunks")
+                        .default_value("4")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("target-bytes")
+                        .long("target-bytes")
+                        .takes_value(true)
+                        .help("size chunks to about this many bytes instead of using --chunks"),
+                )
+                .arg(
+                    Arg::with_name("balance")
+                        .long("balance")
+                        .default_value("lpt")
+                        .possible_values(&["lpt", "compressed"])
+                        .help("weigh files by tar size or by estimated compressed size"),
+                )
+                .arg(
+                    Arg::with_name("group")
+                        .long("group")
+                        .default_value("none")
+                        .possible_values(&["none", "package"])
+                        .help("keep each top level package in a single chunk"),
+                )
                 .arg(
                     Arg::with_name("input")
                         .short("i")
@@ -177,6 +193,96 @@ fn main() -> Result<()> {
                         .long("output")
                         .takes_value(true)
                         .required(true),
+                )
+                .arg(
+                    Arg::with_name("container")
+                        .long("container")
+                        .default_value("tar")
+                        .possible_values(&["tar", "indexed"])
+                        .help("indexed compresses files one by one for random access"),
+                )
+                .arg(
+                    Arg::with_name("codec")
+                        .long("codec")
+                        .default_value("zstd")
+                        .possible_values(&["zstd", "lz4", "gzip", "none"]),
+                )
+                .arg(
+                    Arg::with_name("level")
+                        .short("l")
+                        .long("level")
+                        .default_value("0")
+                        .takes_value(true)
+                        .help("compression level, 0 uses the codec's default"),
+                )
+                .arg(
+                    Arg::with_name("long")
+                        .long("long")
+                        .help("enable long distance matching"),
+                )
+                .arg(
+                    Arg::with_name("window-log")
+                        .long("window-log")
+                        .takes_value(true)
+                        .help("log2 of the zstd window size, decoders need as much memory"),
+                )
+                .arg(
+                    Arg::with_name("workers")
+                        .long("workers")
+                        .default_value("0")
+                        .takes_value(true)
+                        .help("zstd compression threads per chunk"),
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .short("j")
+                        .long("jobs")
+                        .default_value("0")
+                        .takes_value(true)
+                        .help("chunks compressed at once, 0 uses one per core"),
+                )
+                .arg(
+                    Arg::with_name("dictionary")
+                        .long("dictionary")
+                        .takes_value(true)
+                        .conflicts_with("train-dictionary")
+                        .help("compress with an existing zstd dictionary"),
+                )
+                .arg(
+                    Arg::with_name("train-dictionary")
+                        .long("train-dictionary")
+                        .takes_value(true)
+                        .value_name("bytes")
+                        .help("train a zstd dictionary of this size from the input"),
+                )
+                .arg(
+                    Arg::with_name("incremental")
+                        .long("incremental")
+                        .help("reuse the unchanged chunks and chunk count of the previous split"),
+                )
+                .arg(
+                    Arg::with_name("store")
+                        .long("store")
+                        .conflicts_with_all(&[
+                            "dictionary",
+                            "train-dictionary",
+                            "incremental",
+                            "container",
+                        ])
+                        .help("add each file to a content addressed store instead of chunks"),
+                )
+                .arg(
+                    Arg::with_name("tag")
+                        .long("tag")
+                        .default_value("latest")
+                        .takes_value(true)
+                        .help("name of the index written by --store"),
+                )
+                .arg(
+                    Arg::with_name("signing-key")
+                        .long("signing-key")
+                        .takes_value(true)
+                        .help("sign the manifest or index with this secret key"),
                 ),
         )
         .subcommand(
@@ -190,29 +296,269 @@ fn main() -> Result<()> {
                         .required(true),
                 )
                 .arg(
-                    Arg::with_name("host")
-                        .short("h")
-                        .long("host")
+                    Arg::with_name("source")
+                        .short("s")
+                        .long("source")
+                        .alias("host")
+                        .takes_value(true)
+                        .required(true)
+                        .help("http(s):// base URL, file:// URL or directory of split chunks"),
+                )
+                .arg(
+                    Arg::with_name("concurrency")
+                        .long("concurrency")
+                        .default_value("8")
+                        .takes_value(true)
+                        .help("maximum number of chunks fetched at once"),
+                )
+                .args(&rebuild_args())
+                .arg(
+                    Arg::with_name("in-place")
+                        .long("in-place")
+                        .help("write straight into the output instead of swapping it in"),
+                )
+                .arg(
+                    Arg::with_name("delta")
+                        .long("delta")
+                        .help("only fetch chunks changed since the last --delta rebuild"),
+                )
+                .arg(
+                    Arg::with_name("index")
+                        .long("index")
+                        .takes_value(true)
+                        .conflicts_with("delta")
+                        .help("rebuild this tag from a store written by split --store"),
+                )
+                .arg(
+                    Arg::with_name("cache")
+                        .long("cache")
+                        .takes_value(true)
+                        .requires("index")
+                        .help("keep store blobs here and only fetch the ones missing"),
+                )
+                .arg(
+                    Arg::with_name("metrics-json")
+                        .long("metrics-json")
+                        .takes_value(true)
+                        .help("write per chunk timings and throughput to this file"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("bench")
+                .about("rebuild repeatedly and report timings")
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .takes_value(true)
+                        .required(true)
+                        .help("directory emptied and rebuilt on every iteration"),
+                )
+                .arg(
+                    Arg::with_name("source")
+                        .short("s")
+                        .long("source")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .required(true)
+                        .help("split chunks to rebuild from, may be given more than once"),
+                )
+                .arg(
+                    Arg::with_name("concurrency")
+                        .long("concurrency")
+                        .default_value("8")
+                        .takes_value(true)
+                        .use_delimiter(true)
+                        .help("comma separated concurrencies to try with each source"),
+                )
+                .arg(
+                    Arg::with_name("iterations")
+                        .short("n")
+                        .long("iterations")
+                        .default_value("10")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .default_value("table")
+                        .possible_values(&["table", "json"])
+                        .takes_value(true),
+                )
+                .args(&rebuild_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("cat")
+                .about("print one file from chunks split with --container indexed")
+                .arg(
+                    Arg::with_name("source")
+                        .short("s")
+                        .long("source")
+                        .takes_value(true)
+                        .required(true)
+                        .help("http(s):// base URL, file:// URL or directory of split chunks"),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .required(true)
+                        .help("path of the file relative to the split input"),
+                )
+                .args(&source_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("keygen")
+                .about("generate a key pair to sign manifests with")
+                .arg(
+                    Arg::with_name("secret-key")
+                        .long("secret-key")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("public-key")
+                        .long("public-key")
+                        .takes_value(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("serve split chunks over HTTP")
+                .arg(
+                    Arg::with_name("dir")
+                        .short("d")
+                        .long("dir")
                         .takes_value(true)
                         .required(true),
+                )
+                .arg(
+                    Arg::with_name("listen")
+                        .short("l")
+                        .long("listen")
+                        .default_value("0.0.0.0:8080")
+                        .takes_value(true),
                 ),
         )
         .setting(AppSettings::SubcommandRequiredElseHelp)
         .get_matches();
 
-    let chunks_arg = matches.value_of("chunks").unwrap();
-    let chunks_count = chunks_arg.parse::<usize>()?;
-
     if let Some(split_matches) = matches.subcommand_matches("split") {
+        let options = split::SplitOptions {
+            balance: Balance {
+                strategy: split_matches.value_of("balance").unwrap().parse()?,
+                target: match split_matches.value_of("target-bytes") {
+                    Some(bytes) => Target::Bytes(bytes.parse()?),
+                    None => Target::Chunks(split_matches.value_of("chunks").unwrap().parse()?),
+                },
+                grouping: split_matches.value_of("group").unwrap().parse()?,
+            },
+            container: split_matches.value_of("container").unwrap().parse()?,
+            compression: Compression {
+                codec: split_matches.value_of("codec").unwrap().parse()?,
+                level: split_matches.value_of("level").unwrap().parse()?,
+                long_distance_matching: split_matches.is_present("long"),
+                window_log: split_matches
+                    .value_of("window-log")
+                    .map(str::parse)
+                    .transpose()?,
+                workers: split_matches.value_of("workers").unwrap().parse()?,
+                dictionary: None,
+            },
+            jobs: split_matches.value_of("jobs").unwrap().parse()?,
+            dictionary: split_matches.value_of("dictionary").map(PathBuf::from),
+            train_dictionary: split_matches
+                .value_of("train-dictionary")
+                .map(str::parse)
+                .transpose()?,
+            incremental: split_matches.is_present("incremental"),
+            signing_key: split_matches
+                .value_of("signing-key")
+                .map(|path| signing::read_secret_key(Path::new(path)))
+                .transpose()?,
+        };
         let input = split_matches.value_of("input").unwrap();
         let output = split_matches.value_of("output").unwrap();
-        return split(Path::new(input), Path::new(output), chunks_count);
+        if split_matches.is_present("store") {
+            let tag = split_matches.value_of("tag").unwrap();
+            return store::split(Path::new(input), Path::new(output), tag, &options);
+        }
+        return split::split(Path::new(input), Path::new(output), &options);
     }
 
     if let Some(rebuild_matches) = matches.subcommand_matches("rebuild") {
         let output = rebuild_matches.value_of("output").unwrap();
-        let host = rebuild_matches.value_of("host").unwrap();
-        return rebuild(Path::new(output), host, chunks_count);
+        let source = rebuild_matches.value_of("source").unwrap();
+        let concurrency = parse_concurrency(rebuild_matches.value_of("concurrency").unwrap())?;
+        let options = rebuild_options(rebuild_matches, concurrency)?;
+        let index = rebuild_matches.value_of("index");
+        let cache = rebuild_matches.value_of("cache").map(Path::new);
+        let build = |output: &Path| match index {
+            Some(tag) => store::rebuild(output, source, tag, cache, &options),
+            None => rebuild::rebuild(output, source, &options),
+        };
+
+        // A delta rebuild has to update the previous tree where it is
+        let result = if rebuild_matches.is_present("in-place") || options.delta {
+            build(Path::new(output))
+        } else {
+            let sync = options.durability.syncs_swap();
+            staging::staged(Path::new(output), sync, build)
+        };
+
+        let report = options.metrics.report();
+        if let Some(path) = rebuild_matches.value_of("metrics-json") {
+            report.write(Path::new(path))?;
+        }
+        result?;
+        println!("{}", report);
+        return Ok(());
+    }
+
+    if let Some(bench_matches) = matches.subcommand_matches("bench") {
+        let output = bench_matches.value_of("output").unwrap();
+        let sources = bench_matches
+            .values_of("source")
+            .unwrap()
+            .collect::<Vec<_>>();
+        let options = bench::BenchOptions {
+            iterations: bench_matches.value_of("iterations").unwrap().parse()?,
+            concurrency: bench_matches
+                .values_of("concurrency")
+                .unwrap()
+                .map(parse_concurrency)
+                .collect::<Result<_>>()?,
+            format: bench_matches.value_of("format").unwrap().parse()?,
+        };
+        // Each run sets its own concurrency
+        let rebuild_options = rebuild_options(bench_matches, 0)?;
+        return bench::bench(Path::new(output), &sources, &rebuild_options, &options);
+    }
+
+    if let Some(cat_matches) = matches.subcommand_matches("cat") {
+        let source = cat_matches.value_of("source").unwrap();
+        let path = cat_matches.value_of("path").unwrap();
+        let options = indexed::CatOptions {
+            retries: cat_matches.value_of("retries").unwrap().parse()?,
+            backoff: Duration::from_millis(cat_matches.value_of("backoff-ms").unwrap().parse()?),
+            max_window_log: cat_matches.value_of("max-window-log").unwrap().parse()?,
+            public_key: public_key(cat_matches)?,
+        };
+        let data = indexed::cat(source, Path::new(path), &options)?;
+        io::stdout().write_all(&data)?;
+        return Ok(());
+    }
+
+    if let Some(keygen_matches) = matches.subcommand_matches("keygen") {
+        let secret_key = keygen_matches.value_of("secret-key").unwrap();
+        let public_key = keygen_matches.value_of("public-key").unwrap();
+        return signing::keygen(Path::new(secret_key), Path::new(public_key));
+    }
+
+    if let Some(serve_matches) = matches.subcommand_matches("serve") {
+        let dir = serve_matches.value_of("dir").unwrap();
+        let listen = serve_matches.value_of("listen").unwrap();
+        return serve::serve(Path::new(dir), listen);
     }
 
     Ok(())
@@ -0,0 +1,150 @@
+use std::fs::{self, Metadata};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::compression::{Codec, Compression};
+
+pub const MANIFEST_NAME: &str = "manifest.json";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FileEntry {
+    pub path: PathBuf,
+    pub size: u64,
+    pub sha256: String,
+    /// What the chunk's header for the file holds, which an incremental
+    /// split compares too. Manifests from before they were recorded read as
+    /// zero and so never match.
+    #[serde(default)]
+    pub mode: u32,
+    #[serde(default)]
+    pub mtime: i64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LinkEntry {
+    pub path: PathBuf,
+    pub target: PathBuf,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DirectoryEntry {
+    pub path: PathBuf,
+    pub mode: u32,
+    pub mtime: i64,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl DirectoryEntry {
+    pub fn new(path: PathBuf, meta: &Metadata) -> Self {
+        Self {
+            path,
+            mode: meta.mode(),
+            mtime: meta.mtime(),
+            uid: meta.uid(),
+            gid: meta.gid(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ChunkEntry {
+    pub name: String,
+    pub size: u64,
+    pub file_count: usize,
+    pub sha256: String,
+    pub files: Vec<FileEntry>,
+    #[serde(default)]
+    pub links: Vec<LinkEntry>,
+}
+
+/// How the entries of a chunk are laid out.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Container {
+    /// A tar stream compressed as a whole.
+    #[default]
+    Tar,
+    /// Entries compressed one by one with a table of contents at the end,
+    /// see `indexed`.
+    Indexed,
+}
+
+impl FromStr for Container {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Error> {
+        match value {
+            "tar" => Ok(Container::Tar),
+            "indexed" => Ok(Container::Indexed),
+            _ => Err(anyhow!("unknown container {:?}", value)),
+        }
+    }
+}
+
+impl Container {
+    /// The extension of chunks in this container, compressed with `codec`.
+    pub fn extension(self, codec: Codec) -> String {
+        match (self, codec.extension()) {
+            (Container::Tar, Some(extension)) => format!("tar.{}", extension),
+            (Container::Tar, None) => "tar".to_string(),
+            // Only the entries inside are compressed
+            (Container::Indexed, _) => "idx".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Manifest {
+    pub chunks: Vec<ChunkEntry>,
+    #[serde(default)]
+    pub container: Container,
+    #[serde(default)]
+    pub compression: Compression,
+    #[serde(default)]
+    pub directories: Vec<DirectoryEntry>,
+}
+
+impl Manifest {
+    pub fn from_slice(bytes: &[u8]) -> Result<Self> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+
+    /// Reads the manifest at `path`, if there is one.
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        match fs::read(path) {
+            Ok(bytes) => Ok(Some(Self::from_slice(&bytes)?)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    pub fn write(&self, output: &Path) -> Result<()> {
+        self.save(&output.join(MANIFEST_NAME))
+    }
+
+    /// Replaces the manifest at `path` atomically, so it is never seen half
+    /// written by a server already handing out the previous one.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        let file = fs::File::create(&partial)?;
+        serde_json::to_writer_pretty(file, self)?;
+        fs::rename(partial, path)?;
+        Ok(())
+    }
+
+    /// Paths of every file and symlink in the chunks.
+    pub fn entries(&self) -> impl Iterator<Item = &Path> {
+        self.chunks.iter().flat_map(|chunk| {
+            let files = chunk.files.iter().map(|file| file.path.as_path());
+            let links = chunk.links.iter().map(|link| link.path.as_path());
+            files.chain(links)
+        })
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use serde::Serialize;
+
+pub fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// What a `TimedReader` measured so far.
+#[derive(Clone, Copy, Default)]
+pub struct Timing {
+    pub first_byte: Option<Duration>,
+    pub elapsed: Duration,
+    pub bytes: u64,
+}
+
+/// Counts the bytes read through the inner reader and the time spent waiting
+/// on it, along with when the first byte arrived.
+pub struct TimedReader<R> {
+    inner: R,
+    started: Instant,
+    timing: Timing,
+}
+
+impl<R: Read> TimedReader<R> {
+    /// `started` is when the request for `inner` was made, which the time to
+    /// first byte is measured from.
+    pub fn new(inner: R, started: Instant) -> Self {
+        Self {
+            inner,
+            started,
+            timing: Timing::default(),
+        }
+    }
+
+    pub fn timing(&self) -> Timing {
+        self.timing
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for TimedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let started = Instant::now();
+        let read = self.inner.read(buf)?;
+        self.timing.elapsed += started.elapsed();
+        self.timing.bytes += read as u64;
+        if read > 0 && self.timing.first_byte.is_none() {
+            self.timing.first_byte = Some(self.started.elapsed());
+        }
+        Ok(read)
+    }
+}
+
+/// Timings of one attempt at fetching a chunk, or a blob in a store.
+#[derive(Serialize)]
+pub struct ChunkMetrics {
+    pub name: String,
+    #[serde(skip)]
+    started: Instant,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub files: u64,
+    pub ttfb_ms: f64,
+    pub download_ms: f64,
+    pub decompress_ms: f64,
+    pub unpack_ms: f64,
+    pub verify_ms: f64,
+    pub fsync_ms: f64,
+    pub total_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ChunkMetrics {
+    pub fn start(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            started: Instant::now(),
+            bytes_in: 0,
+            bytes_out: 0,
+            files: 0,
+            ttfb_ms: 0.0,
+            download_ms: 0.0,
+            decompress_ms: 0.0,
+            unpack_ms: 0.0,
+            verify_ms: 0.0,
+            fsync_ms: 0.0,
+            total_ms: 0.0,
+            error: None,
+        }
+    }
+
+    pub fn started(&self) -> Instant {
+        self.started
+    }
+
+    /// Takes the byte counts and timings of a finished stream, `body` being
+    /// what was read from the source and `decoder` what it decompressed to.
+    /// Reading from the decoder includes waiting on the source, which is
+    /// taken out of the decompression time.
+    pub fn streamed(&mut self, body: Timing, decoder: Timing) {
+        self.bytes_in = body.bytes;
+        self.bytes_out = decoder.bytes;
+        self.ttfb_ms = millis(body.first_byte.unwrap_or_default());
+        self.download_ms = millis(body.elapsed);
+        self.decompress_ms = millis(decoder.elapsed.saturating_sub(body.elapsed));
+    }
+}
+
+/// Collects the timings of a rebuild, from every fetch thread.
+pub struct Metrics {
+    started: Instant,
+    phases: Mutex<BTreeMap<&'static str, Duration>>,
+    chunks: Mutex<Vec<ChunkMetrics>>,
+}
+
+impl Metrics {
+    pub fn start() -> Self {
+        Self {
+            started: Instant::now(),
+            phases: Mutex::new(BTreeMap::new()),
+            chunks: Mutex::new(vec![]),
+        }
+    }
+
+    /// Runs `f`, adding the time it took to `phase`.
+    pub fn time<T, F: FnOnce() -> T>(&self, phase: &'static str, f: F) -> T {
+        let started = Instant::now();
+        let result = f();
+        *self.phases.lock().unwrap().entry(phase).or_default() += started.elapsed();
+        result
+    }
+
+    /// Records one attempt at a chunk, whether it succeeded or not.
+    pub fn record<T>(&self, mut chunk: ChunkMetrics, result: &Result<T>) {
+        chunk.total_ms = millis(chunk.started.elapsed());
+        if let Err(error) = result {
+            chunk.error = Some(format!("{:#}", error));
+        }
+        self.chunks.lock().unwrap().push(chunk);
+    }
+
+    /// Summarizes everything recorded so far. Totals only count the attempts
+    /// that succeeded.
+    pub fn report(&self) -> Report {
+        let total = self.started.elapsed();
+        let chunks = std::mem::take(&mut *self.chunks.lock().unwrap());
+        let succeeded = || chunks.iter().filter(|chunk| chunk.error.is_none());
+        let bytes_out = succeeded().map(|chunk| chunk.bytes_out).sum::<u64>();
+
+        Report {
+            total_ms: millis(total),
+            bytes_in: succeeded().map(|chunk| chunk.bytes_in).sum(),
+            bytes_out,
+            files: succeeded().map(|chunk| chunk.files).sum(),
+            throughput_mib_s: bytes_out as f64 / (1024.0 * 1024.0) / total.as_secs_f64(),
+            failed_attempts: chunks.len() - succeeded().count(),
+            phases_ms: self
+                .phases
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(&phase, &duration)| (phase, millis(duration)))
+                .collect(),
+            chunks,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    pub total_ms: f64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub files: u64,
+    pub throughput_mib_s: f64,
+    pub failed_attempts: usize,
+    pub phases_ms: BTreeMap<&'static str, f64>,
+    pub chunks: Vec<ChunkMetrics>,
+}
+
+impl Report {
+    pub fn write(&self, path: &Path) -> Result<()> {
+        serde_json::to_writer_pretty(File::create(path)?, self)?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "rebuilt {} files, {} bytes in, {} bytes out in {:.1}ms, {:.1} MiB/s",
+            self.files, self.bytes_in, self.bytes_out, self.total_ms, self.throughput_mib_s
+        )
+    }
+}
@@ -0,0 +1,317 @@
+use std::ffi::{CStr, CString};
+use std::fs::{File, Permissions};
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{fchown, FileExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{Scope, ScopedJoinHandle};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use tar::{Entry, EntryType, Header};
+
+use crate::rebuild::Preserve;
+
+/// Files at least this large are preallocated and written in pieces, which
+/// several workers can write at once. Smaller ones are written whole.
+const LARGE_FILE: u64 = 4 * 1024 * 1024;
+const PIECE_SIZE: u64 = 1024 * 1024;
+
+/// Jobs queued per worker before decoding waits for them to catch up, which
+/// bounds the memory held by decoded but unwritten files.
+const QUEUE_PER_WORKER: usize = 4;
+
+/// The metadata of a tar entry that's restored on the file written for it.
+#[derive(Clone, Copy)]
+pub struct Metadata {
+    pub mode: u32,
+    pub mtime: u64,
+    pub uid: u64,
+    pub gid: u64,
+}
+
+impl Metadata {
+    pub fn from_header(header: &Header) -> io::Result<Self> {
+        Ok(Self {
+            mode: header.mode()?,
+            mtime: header.mtime()?,
+            uid: header.uid()?,
+            gid: header.gid()?,
+        })
+    }
+
+    /// Applies metadata the same way tar does.
+    pub fn restore(&self, file: &File, preserve: Preserve) -> io::Result<()> {
+        if preserve.ownership {
+            fchown(file, Some(self.uid as u32), Some(self.gid as u32))?;
+        }
+
+        let mode = if preserve.permissions {
+            self.mode & 0o7777
+        } else {
+            self.mode & 0o777
+        };
+        file.set_permissions(Permissions::from_mode(mode))?;
+
+        if preserve.mtime {
+            file.set_modified(UNIX_EPOCH + Duration::from_secs(self.mtime))?;
+        }
+        Ok(())
+    }
+}
+
+fn openat2(dir: &File, path: &CStr, flags: i32) -> io::Result<File> {
+    // libc can add fields to `open_how`, which the kernel takes as zero
+    let mut how: libc::open_how = unsafe { mem::zeroed() };
+    how.flags = (flags | libc::O_CLOEXEC) as u64;
+    // The kernel refuses a mode unless the file may be created
+    if flags & libc::O_CREAT != 0 {
+        how.mode = 0o600;
+    }
+    how.resolve = libc::RESOLVE_BENEATH;
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            dir.as_raw_fd(),
+            path.as_ptr(),
+            &how as *const libc::open_how,
+            mem::size_of::<libc::open_how>(),
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd as i32) })
+}
+
+/// Checks that the kernel has `openat2`, which workers open files with.
+pub fn probe() -> io::Result<()> {
+    let dir = File::open(".")?;
+    openat2(
+        &dir,
+        &CString::new(".")?,
+        libc::O_RDONLY | libc::O_DIRECTORY,
+    )?;
+    Ok(())
+}
+
+/// Creates `path` under `dir` for writing, replacing whatever an earlier
+/// rebuild left there if it's a symlink. `RESOLVE_BENEATH` keeps symlinks
+/// from leading it out of `dir`.
+fn create_beneath(dir: &File, path: &CStr) -> io::Result<File> {
+    let flags = libc::O_CREAT | libc::O_WRONLY | libc::O_TRUNC | libc::O_NOFOLLOW;
+    match openat2(dir, path, flags) {
+        Err(error) if error.raw_os_error() == Some(libc::ELOOP) => {
+            if unsafe { libc::unlinkat(dir.as_raw_fd(), path.as_ptr(), 0) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            openat2(dir, path, flags)
+        }
+        result => result,
+    }
+}
+
+/// A large file being written in pieces. Whichever worker writes the last
+/// one restores the metadata, so later writes don't bump the mtime.
+struct LargeFile {
+    file: File,
+    metadata: Metadata,
+    remaining: AtomicUsize,
+}
+
+enum Job {
+    File {
+        path: CString,
+        data: Vec<u8>,
+        metadata: Metadata,
+    },
+    Piece {
+        file: Arc<LargeFile>,
+        offset: u64,
+        data: Vec<u8>,
+    },
+}
+
+/// What every worker shares.
+struct Shared {
+    output: File,
+    preserve: Preserve,
+    sync: bool,
+}
+
+impl Shared {
+    /// Restores the metadata of a fully written file and fsyncs it if asked
+    /// to, returning the time the fsync took.
+    fn finish(&self, file: &File, metadata: &Metadata) -> io::Result<Duration> {
+        metadata.restore(file, self.preserve)?;
+        if !self.sync {
+            return Ok(Duration::ZERO);
+        }
+        let started = Instant::now();
+        file.sync_all()?;
+        Ok(started.elapsed())
+    }
+
+    fn run(&self, job: Job) -> io::Result<Duration> {
+        match job {
+            Job::File {
+                path,
+                data,
+                metadata,
+            } => {
+                let file = create_beneath(&self.output, &path)?;
+                file.write_all_at(&data, 0)?;
+                self.finish(&file, &metadata)
+            }
+            Job::Piece { file, offset, data } => {
+                file.file.write_all_at(&data, offset)?;
+                if file.remaining.fetch_sub(1, Ordering::AcqRel) == 1 {
+                    return self.finish(&file.file, &file.metadata);
+                }
+                Ok(Duration::ZERO)
+            }
+        }
+    }
+
+    /// Runs jobs until the queue is closed, returning the time spent on
+    /// fsync.
+    fn work(&self, jobs: &Mutex<Receiver<Job>>) -> io::Result<Duration> {
+        let mut synced = Duration::ZERO;
+        loop {
+            let job = jobs.lock().unwrap().recv();
+            match job {
+                Ok(job) => synced += self.run(job)?,
+                Err(_) => return Ok(synced),
+            }
+        }
+    }
+}
+
+/// Writes the regular files of a tar stream from a pool of workers, while
+/// the thread decoding the stream goes on to the next entries. Directories
+/// and everything else are left to tar on the decoding thread, so they're
+/// there before any later file in them is queued.
+pub struct PoolWriter<'scope> {
+    shared: Arc<Shared>,
+    jobs: Option<SyncSender<Job>>,
+    workers: Vec<ScopedJoinHandle<'scope, io::Result<Duration>>>,
+}
+
+impl<'scope> PoolWriter<'scope> {
+    pub fn start<'env>(
+        scope: &'scope Scope<'scope, 'env>,
+        output: &Path,
+        workers: usize,
+        preserve: Preserve,
+        sync: bool,
+    ) -> io::Result<Self> {
+        let shared = Arc::new(Shared {
+            output: File::open(output)?,
+            preserve,
+            sync,
+        });
+        let workers = workers.max(1);
+        let (sender, receiver) = mpsc::sync_channel(workers * QUEUE_PER_WORKER);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..workers)
+            .map(|_| {
+                let shared = shared.clone();
+                let receiver = receiver.clone();
+                scope.spawn(move || shared.work(&receiver))
+            })
+            .collect();
+
+        Ok(Self {
+            shared,
+            jobs: Some(sender),
+            workers,
+        })
+    }
+
+    /// Whether `push` takes this entry, otherwise it goes through tar.
+    pub fn accepts<R: Read>(entry: &Entry<R>) -> bool {
+        matches!(
+            entry.header().entry_type(),
+            EntryType::Regular | EntryType::Continuous
+        )
+    }
+
+    fn send(&mut self, job: Job) -> io::Result<()> {
+        let sent = match &self.jobs {
+            Some(jobs) => jobs.send(job).is_ok(),
+            None => false,
+        };
+        if sent {
+            return Ok(());
+        }
+
+        // Every worker stopped, so one of them failed
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap()?;
+        }
+        Err(io::Error::other("every write worker stopped"))
+    }
+
+    pub fn push<R: Read>(&mut self, path: &Path, entry: &mut Entry<R>) -> io::Result<()> {
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        let metadata = Metadata::from_header(entry.header())?;
+        let size = entry.size();
+
+        if size < LARGE_FILE {
+            let mut data = Vec::with_capacity(size as usize);
+            entry.read_to_end(&mut data)?;
+            return self.send(Job::File {
+                path,
+                data,
+                metadata,
+            });
+        }
+
+        let file = create_beneath(&self.shared.output, &path)?;
+        // Only an optimization, not every filesystem can preallocate
+        unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, size as libc::off_t) };
+
+        let file = Arc::new(LargeFile {
+            file,
+            metadata,
+            remaining: AtomicUsize::new(size.div_ceil(PIECE_SIZE) as usize),
+        });
+        let mut offset = 0;
+        while offset < size {
+            let mut data = vec![0; PIECE_SIZE.min(size - offset) as usize];
+            entry.read_exact(&mut data)?;
+            let len = data.len() as u64;
+            self.send(Job::Piece {
+                file: file.clone(),
+                offset,
+                data,
+            })?;
+            offset += len;
+        }
+        Ok(())
+    }
+
+    /// Waits for every queued file to be written and returns the time the
+    /// workers spent on fsync.
+    pub fn finish(mut self) -> io::Result<Duration> {
+        self.jobs = None;
+        let mut synced = Duration::ZERO;
+        let mut failed = None;
+        for worker in self.workers.drain(..) {
+            match worker.join().unwrap() {
+                Ok(duration) => synced += duration,
+                Err(error) => failed = failed.or(Some(error)),
+            }
+        }
+        match failed {
+            Some(error) => Err(error),
+            None => Ok(synced),
+        }
+    }
+}
@@ -0,0 +1,719 @@
+use std::collections::HashSet;
+use std::fs::{self, Permissions};
+use std::io::{self, BufRead, BufReader, Read};
+use std::os::unix::fs::{fchown, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use anyhow::{anyhow, Error, Result};
+use ed25519_dalek::PublicKey;
+use tar::Archive;
+use tokio::runtime::{self, Runtime};
+use tokio::sync::Semaphore;
+use tokio::task;
+use zstd::zstd_safe::DCtx;
+
+use crate::compression::Compression;
+use crate::durability::{self, Durability};
+use crate::error::RebuildError;
+use crate::fetch::Fetcher;
+use crate::hash::{sha256_hex, sha256_reader, HashReader};
+use crate::indexed::{self, IndexReader, Kind};
+use crate::manifest::{ChunkEntry, Container, DirectoryEntry, Manifest, MANIFEST_NAME};
+use crate::metrics::{self, ChunkMetrics, Metrics, TimedReader, Timing};
+use crate::pool::{self, PoolWriter};
+use crate::safety::{self, Budget, Limits, Links};
+use crate::signing;
+use crate::source::{self, ChunkSource};
+#[cfg(feature = "io-uring")]
+use crate::uring::{self, UringWriter};
+
+/// Which metadata recorded by split is restored. Executable bits are always
+/// kept, `permissions` adds the setuid, setgid and sticky bits.
+#[derive(Clone, Copy)]
+pub struct Preserve {
+    pub permissions: bool,
+    pub mtime: bool,
+    pub ownership: bool,
+}
+
+/// How chunk entries are written to the output.
+#[derive(Clone, Copy, Debug)]
+pub enum Writer {
+    /// One entry after another with `tar::Entry::unpack_in`.
+    Tar,
+    /// Files from a pool of workers while the next entries are decoded,
+    /// large files preallocated and written in pieces.
+    Pool,
+    /// Small files in batches through io_uring, the rest with tar.
+    #[cfg(feature = "io-uring")]
+    IoUring,
+}
+
+impl FromStr for Writer {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Error> {
+        match value {
+            "tar" => Ok(Writer::Tar),
+            "pool" => Ok(Writer::Pool),
+            #[cfg(feature = "io-uring")]
+            "io-uring" => Ok(Writer::IoUring),
+            #[cfg(not(feature = "io-uring"))]
+            "io-uring" => Err(anyhow!("fs-rebuild was built without the io-uring feature")),
+            _ => Err(anyhow!("unknown writer {:?}", value)),
+        }
+    }
+}
+
+impl Writer {
+    /// Falls back to tar when the kernel, or a sandbox in front of it, doesn't
+    /// provide what the writer needs.
+    fn available(self) -> Self {
+        match self {
+            Writer::Tar => Writer::Tar,
+            Writer::Pool => match pool::probe() {
+                Ok(()) => Writer::Pool,
+                Err(error) => {
+                    eprintln!("openat2 isn't available, writing with tar: {}", error);
+                    Writer::Tar
+                }
+            },
+            #[cfg(feature = "io-uring")]
+            Writer::IoUring => match uring::probe() {
+                Ok(()) => Writer::IoUring,
+                Err(error) => {
+                    eprintln!("io_uring isn't available, writing with tar: {}", error);
+                    Writer::Tar
+                }
+            },
+        }
+    }
+}
+
+/// Where a `--delta` rebuild records what it wrote, for the next one to
+/// compare against.
+const LOCAL_MANIFEST_NAME: &str = ".fs-rebuild-manifest.json";
+
+/// Forgets what the last `--delta` rebuild wrote before `output` changes
+/// under it, by any rebuild. Only a delta rebuild that finishes records it
+/// again, so the next one never skips chunks whose files were replaced.
+pub fn forget_delta(output: &Path) -> io::Result<()> {
+    match fs::remove_file(output.join(LOCAL_MANIFEST_NAME)) {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Clone)]
+pub struct RebuildOptions {
+    pub retries: u32,
+    pub backoff: Duration,
+    pub refetch_rounds: u32,
+    pub concurrency: usize,
+    pub preserve: Preserve,
+    pub delta: bool,
+    pub limits: Limits,
+    pub public_key: Option<PublicKey>,
+    pub metrics: Arc<Metrics>,
+    pub writer: Writer,
+    pub write_workers: usize,
+    pub durability: Durability,
+}
+
+/// Everything shared by the chunks of one rebuild.
+struct Context {
+    output: PathBuf,
+    source: Box<dyn ChunkSource>,
+    container: Container,
+    compression: Compression,
+    dictionary: Vec<u8>,
+    preserve: Preserve,
+    limits: Limits,
+    links: Links,
+    budget: Budget,
+    metrics: Arc<Metrics>,
+    writer: Writer,
+    write_workers: usize,
+    durability: Durability,
+}
+
+pub fn fetch_manifest(
+    source: &dyn ChunkSource,
+    public_key: Option<&PublicKey>,
+) -> Result<Manifest> {
+    let bytes = signing::get_verified(source, MANIFEST_NAME, public_key)?;
+    Manifest::from_slice(&bytes)
+}
+
+pub fn fetch_dictionary(source: &dyn ChunkSource, compression: &Compression) -> Result<Vec<u8>> {
+    let entry = match &compression.dictionary {
+        Some(entry) => entry,
+        None => return Ok(vec![]),
+    };
+
+    let bytes = source.get(&entry.name)?;
+    let sha256 = sha256_hex(&bytes);
+    if sha256 != entry.sha256 {
+        return Err(RebuildError::DictionaryChecksum {
+            name: entry.name.clone(),
+            expected: entry.sha256.clone(),
+            actual: sha256,
+        }
+        .into());
+    }
+
+    Ok(bytes)
+}
+
+/// Checks each entry before unpacking it and charges it to the rebuild's
+/// budget. `charged` keeps what this chunk used, to give it back if it fails.
+/// Files are fsynced as they're written if the durability asks for it.
+fn unpack<R: Read>(
+    context: &Context,
+    archive: &mut Archive<R>,
+    charged: &mut (u64, u64),
+    timings: &mut ChunkMetrics,
+) -> Result<()> {
+    let sync = context.durability.per_file();
+    let mut synced = Duration::ZERO;
+
+    thread::scope(|scope| -> Result<()> {
+        let mut pool = match context.writer {
+            Writer::Pool => Some(PoolWriter::start(
+                scope,
+                &context.output,
+                context.write_workers,
+                context.preserve,
+                sync,
+            )?),
+            _ => None,
+        };
+
+        #[cfg(feature = "io-uring")]
+        let mut batch = match context.writer {
+            Writer::IoUring => Some(UringWriter::new(&context.output, context.preserve, sync)?),
+            _ => None,
+        };
+
+        let mut links = context.links.clone();
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            safety::check_entry(&entry, &mut links)?;
+
+            let size = entry.header().size()?;
+            charged.0 += size;
+            charged.1 += 1;
+            context.budget.charge(size)?;
+
+            if entry.header().entry_type().is_file() {
+                timings.files += 1;
+            }
+
+            if let Some(pool) = &mut pool {
+                if PoolWriter::accepts(&entry) {
+                    let path = entry.path()?.into_owned();
+                    pool.push(&path, &mut entry)?;
+                    continue;
+                }
+            }
+
+            #[cfg(feature = "io-uring")]
+            if let Some(batch) = &mut batch {
+                if UringWriter::accepts(&entry) {
+                    let path = entry.path()?.into_owned();
+                    batch.push(&path, &mut entry)?;
+                    continue;
+                }
+                // Keeps the entries in order
+                batch.flush()?;
+            }
+
+            // Also refuses to write through a symlink leading out of the output
+            entry.unpack_in(&context.output)?;
+
+            if sync && entry.header().entry_type().is_file() {
+                let started = Instant::now();
+                durability::sync_path(&context.output.join(entry.path()?))?;
+                synced += started.elapsed();
+            }
+        }
+
+        if let Some(pool) = pool {
+            synced += pool.finish()?;
+        }
+
+        #[cfg(feature = "io-uring")]
+        if let Some(batch) = &mut batch {
+            batch.flush()?;
+            synced += batch.synced();
+        }
+        Ok(())
+    })?;
+
+    timings.fsync_ms = metrics::millis(synced);
+    Ok(())
+}
+
+/// Streams a chunk through decompression and extraction, so memory use
+/// doesn't depend on the chunk size. The chunk checksum can only be checked
+/// once the stream ends, after its files were written, which is why every
+/// file is verified again afterwards.
+fn fetch_chunk(context: &Context, chunk: &ChunkEntry) -> Result<()> {
+    let mut charged = (0, 0);
+    let mut timings = ChunkMetrics::start(&chunk.name);
+    let result = fetch_chunk_charged(context, chunk, &mut charged, &mut timings);
+    if result.is_err() {
+        context.budget.release(charged.0, charged.1);
+    }
+    context.metrics.record(timings, &result);
+    result
+}
+
+/// Unpacks a tar chunk through its decoder. Returns the compressed stream,
+/// with whatever the decoder didn't need, and what the decoder read.
+fn unpack_tar<R: BufRead>(
+    context: &Context,
+    compressed: R,
+    charged: &mut (u64, u64),
+    timings: &mut ChunkMetrics,
+) -> Result<(R, Timing)> {
+    let decoder = TimedReader::new(
+        context.compression.decoder(
+            compressed,
+            &context.dictionary,
+            context.limits.max_window_log,
+        )?,
+        timings.started(),
+    );
+
+    let mut archive = Archive::new(decoder);
+    archive.set_preserve_permissions(context.preserve.permissions);
+    archive.set_preserve_mtime(context.preserve.mtime);
+    archive.set_preserve_ownerships(context.preserve.ownership);
+    unpack(context, &mut archive, charged, timings)?;
+    let mut decoder = archive.into_inner();
+
+    // Drain whatever tar didn't need so the whole chunk is hashed
+    io::copy(&mut decoder, &mut io::sink())?;
+    let decoded = decoder.timing();
+    Ok((decoder.into_inner().finish(), decoded))
+}
+
+/// Unpacks the entries of an indexed chunk as they stream in, each through
+/// its own decoder, and stops at the table of contents. Files are written
+/// on this thread whatever the writer.
+fn unpack_indexed<R: BufRead>(
+    context: &Context,
+    compressed: R,
+    charged: &mut (u64, u64),
+    timings: &mut ChunkMetrics,
+) -> Result<(R, Timing)> {
+    let sync = context.durability.per_file();
+    let mut synced = Duration::ZERO;
+    let mut decoded = Timing::default();
+    let mut entries = IndexReader::new(compressed);
+    let mut links = context.links.clone();
+
+    while let Some(entry) = entries.next_entry()? {
+        safety::check_index_entry(&entry, &mut links)?;
+
+        charged.0 += entry.size;
+        charged.1 += 1;
+        context.budget.charge(entry.size)?;
+
+        if entry.kind == Kind::File {
+            timings.files += 1;
+        }
+
+        let decoder = context.compression.decoder(
+            entries.data(&entry),
+            &context.dictionary,
+            context.limits.max_window_log,
+        )?;
+        // Stops at the size charged for, verifying the files catches the rest
+        let mut decoder = TimedReader::new(decoder.take(entry.size), timings.started());
+        synced += indexed::unpack_entry(
+            &context.output,
+            &entry,
+            &mut decoder,
+            context.preserve,
+            sync,
+        )?;
+
+        decoded.elapsed += decoder.timing().elapsed;
+        decoded.bytes += decoder.timing().bytes;
+        let mut data = decoder.into_inner().into_inner().finish();
+        io::copy(&mut data, &mut io::sink())?;
+    }
+
+    timings.fsync_ms = metrics::millis(synced);
+    Ok((entries.into_inner(), decoded))
+}
+
+fn fetch_chunk_charged(
+    context: &Context,
+    chunk: &ChunkEntry,
+    charged: &mut (u64, u64),
+    timings: &mut ChunkMetrics,
+) -> Result<()> {
+    let body = TimedReader::new(context.source.open(&chunk.name)?, timings.started());
+    let compressed = BufReader::with_capacity(DCtx::in_size(), HashReader::new(body));
+
+    let started = Instant::now();
+    let (mut compressed, decoded) = match context.container {
+        Container::Tar => unpack_tar(context, compressed, charged, timings)?,
+        Container::Indexed => unpack_indexed(context, compressed, charged, timings)?,
+    };
+    let unpacked = started.elapsed().saturating_sub(decoded.elapsed);
+    // The pool's workers fsync in parallel, so their total can exceed this
+    timings.unpack_ms = (metrics::millis(unpacked) - timings.fsync_ms).max(0.0);
+
+    io::copy(&mut compressed, &mut io::sink())?;
+    let (body, sha256, size) = compressed.into_inner().finish();
+    timings.streamed(body.timing(), decoded);
+
+    if size != chunk.size {
+        return Err(RebuildError::ChunkSize {
+            name: chunk.name.clone(),
+            expected: chunk.size,
+            actual: size,
+        }
+        .into());
+    }
+
+    if sha256 != chunk.sha256 {
+        return Err(RebuildError::ChunkChecksum {
+            name: chunk.name.clone(),
+            expected: chunk.sha256.clone(),
+            actual: sha256,
+        }
+        .into());
+    }
+
+    let started = Instant::now();
+    verify_chunk(&context.output, chunk)?;
+    timings.verify_ms = metrics::millis(started.elapsed());
+    Ok(())
+}
+
+fn verify_chunk(output: &Path, chunk: &ChunkEntry) -> Result<()> {
+    for file in chunk.files.iter() {
+        let path = output.join(&file.path);
+        if !path.is_file() {
+            return Err(RebuildError::MissingFile { path }.into());
+        }
+
+        let sha256 = sha256_reader(durability::open_restored(&path)?)?;
+        if sha256 != file.sha256 {
+            return Err(RebuildError::CorruptFile {
+                path,
+                expected: file.sha256.clone(),
+                actual: sha256,
+            }
+            .into());
+        }
+    }
+
+    for link in chunk.links.iter() {
+        let path = output.join(&link.path);
+        let target = match fs::read_link(&path) {
+            Ok(target) => target,
+            Err(_) => return Err(RebuildError::MissingFile { path }.into()),
+        };
+
+        if target != link.target {
+            return Err(RebuildError::LinkTarget {
+                path,
+                expected: link.target.clone(),
+                actual: target,
+            }
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Gives the owner full access to a directory an earlier rebuild left, whose
+/// restored mode, like 0555, would keep anyone but root from writing to it.
+/// `restore_directories` puts the recorded mode back afterwards.
+fn make_writable(path: &Path, meta: &fs::Metadata) -> io::Result<()> {
+    let mode = meta.permissions().mode();
+    if mode & 0o700 == 0o700 {
+        return Ok(());
+    }
+    fs::set_permissions(path, Permissions::from_mode(mode | 0o700))
+}
+
+/// Creates the directories parents first, replacing whatever else an earlier
+/// tree left in their place instead of following it, and makes the ones
+/// already there writable.
+pub fn create_directories(output: &Path, directories: &[DirectoryEntry]) -> Result<()> {
+    fs::create_dir_all(output)?;
+    make_writable(output, &fs::metadata(output)?)?;
+
+    let mut directories = directories.iter().collect::<Vec<_>>();
+    directories.sort_by_key(|directory| directory.path.components().count());
+    for directory in directories {
+        let path = output.join(&directory.path);
+        safety::check_beneath(output, &path)?;
+        match fs::symlink_metadata(&path) {
+            Ok(meta) if meta.is_dir() => make_writable(&path, &meta)?,
+            Ok(_) => {
+                fs::remove_file(&path)?;
+                fs::create_dir(&path)?;
+            }
+            Err(error) if error.kind() == io::ErrorKind::NotFound => fs::create_dir(&path)?,
+            Err(error) => return Err(error.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Applies directory metadata once every chunk is unpacked, children before
+/// their parents, so later writes don't bump the mtimes and read-only
+/// directories don't block their own contents.
+pub fn restore_directories(
+    output: &Path,
+    directories: &[DirectoryEntry],
+    preserve: Preserve,
+) -> Result<()> {
+    for directory in directories.iter().rev() {
+        let path = output.join(&directory.path);
+        safety::check_beneath(output, &path)?;
+        if !fs::symlink_metadata(&path)?.is_dir() {
+            return Err(RebuildError::UnsafePath { path }.into());
+        }
+        // Through one handle, since the mode may not let it be opened again
+        let handle = durability::open_restored(&path)?;
+
+        if preserve.ownership {
+            fchown(&handle, Some(directory.uid), Some(directory.gid))?;
+        }
+
+        let mode = if preserve.permissions {
+            directory.mode & 0o7777
+        } else {
+            directory.mode & 0o777
+        };
+        handle.set_permissions(Permissions::from_mode(mode))?;
+
+        if preserve.mtime && directory.mtime >= 0 {
+            let mtime = UNIX_EPOCH + Duration::from_secs(directory.mtime as u64);
+            handle.set_modified(mtime)?;
+        }
+    }
+    Ok(())
+}
+
+/// Deletes what the previous rebuild wrote that no longer exists upstream.
+fn remove_stale(output: &Path, local: &Manifest, manifest: &Manifest) -> Result<()> {
+    // What's stale may sit in directories the previous rebuild made read-only
+    make_writable(output, &fs::metadata(output)?)?;
+    for directory in local.directories.iter() {
+        let path = output.join(&directory.path);
+        match safety::check_beneath(output, &path).and_then(|()| fs::symlink_metadata(&path)) {
+            Ok(meta) if meta.is_dir() => make_writable(&path, &meta)?,
+            Ok(_) => {}
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+            Err(error) => return Err(error.into()),
+        }
+    }
+
+    let current = manifest.entries().collect::<HashSet<_>>();
+    let mut removed = 0;
+    for path in local.entries().filter(|path| !current.contains(path)) {
+        let path = output.join(path);
+        match safety::check_beneath(output, &path).and_then(|()| fs::remove_file(&path)) {
+            Ok(()) => removed += 1,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+            Err(error) => return Err(error.into()),
+        }
+    }
+
+    let directories = manifest
+        .directories
+        .iter()
+        .map(|directory| directory.path.as_path())
+        .collect::<HashSet<_>>();
+    for directory in local.directories.iter().rev() {
+        if directories.contains(directory.path.as_path()) {
+            continue;
+        }
+        let path = output.join(&directory.path);
+        match safety::check_beneath(output, &path).and_then(|()| fs::remove_dir_all(&path)) {
+            Ok(()) => removed += 1,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+            Err(error) => return Err(error.into()),
+        }
+    }
+
+    println!("removed {} stale entries", removed);
+    Ok(())
+}
+
+/// Runs `fetch` over `items` with at most `concurrency` in flight and returns
+/// the ones that failed. Each fetch's decode and write pipeline is blocking,
+/// so it runs on the runtime's blocking pool, while the network IO for all of
+/// them is multiplexed on the HTTP client's own event loop.
+fn fetch_round<T, F>(
+    runtime: &Runtime,
+    items: Vec<T>,
+    concurrency: usize,
+    fetch: &Arc<F>,
+) -> Vec<(T, anyhow::Error)>
+where
+    T: Send + 'static,
+    F: Fn(&T) -> Result<()> + Send + Sync + 'static,
+{
+    runtime.block_on(async {
+        let semaphore = Arc::new(Semaphore::new(concurrency));
+        let mut tasks = vec![];
+
+        for item in items {
+            let permit = semaphore.clone().acquire_owned().await.unwrap();
+            let fetch = fetch.clone();
+            tasks.push(task::spawn_blocking(move || {
+                let result = fetch(&item);
+                drop(permit);
+                (item, result)
+            }));
+        }
+
+        let mut failed = vec![];
+        for task in tasks {
+            if let (item, Err(error)) = task.await.unwrap() {
+                failed.push((item, error));
+            }
+        }
+        failed
+    })
+}
+
+/// Fetches every item, then re-fetches the ones that failed for up to
+/// `refetch_rounds` more rounds. Returns the names of those that never
+/// succeeded.
+pub fn fetch_all<T, N, F>(
+    items: Vec<T>,
+    name: N,
+    options: &RebuildOptions,
+    fetch: F,
+) -> Result<Vec<String>>
+where
+    T: Send + 'static,
+    N: Fn(&T) -> String,
+    F: Fn(&T) -> Result<()> + Send + Sync + 'static,
+{
+    let runtime = runtime::Builder::new_current_thread().build()?;
+    let fetch = Arc::new(fetch);
+    let mut pending = items;
+    let mut round = 0;
+
+    loop {
+        let failed = fetch_round(&runtime, pending, options.concurrency, &fetch);
+        if failed.is_empty() {
+            return Ok(vec![]);
+        }
+
+        for (item, error) in failed.iter() {
+            eprintln!("{} failed: {:#}", name(item), error);
+        }
+
+        if round == options.refetch_rounds {
+            return Ok(failed.iter().map(|(item, _)| name(item)).collect());
+        }
+
+        round += 1;
+        pending = failed.into_iter().map(|(item, _)| item).collect();
+        println!("refetching {} that failed", pending.len());
+    }
+}
+
+pub fn rebuild(output: &Path, location: &str, options: &RebuildOptions) -> Result<()> {
+    let fetcher = Fetcher::new(options.retries, options.backoff);
+    let source = source::from_location(location, fetcher);
+    let (manifest, links, dictionary) = options.metrics.time("manifest", || -> Result<_> {
+        let manifest = fetch_manifest(source.as_ref(), options.public_key.as_ref())?;
+        let links = safety::check_manifest(&manifest, &options.limits)?;
+        let dictionary = fetch_dictionary(source.as_ref(), &manifest.compression)?;
+        Ok((manifest, links, dictionary))
+    })?;
+
+    let context = Context {
+        output: output.to_path_buf(),
+        source,
+        container: manifest.container,
+        compression: manifest.compression.clone(),
+        dictionary,
+        preserve: options.preserve,
+        limits: options.limits,
+        links,
+        budget: Budget::new(options.limits),
+        metrics: options.metrics.clone(),
+        writer: options.writer.available(),
+        write_workers: options.write_workers,
+        durability: options.durability,
+    };
+
+    let local = if options.delta {
+        Manifest::load(&output.join(LOCAL_MANIFEST_NAME))?
+    } else {
+        None
+    };
+    forget_delta(output)?;
+
+    let mut pending = manifest.chunks.clone();
+    if let Some(local) = &local {
+        options
+            .metrics
+            .time("prune", || remove_stale(output, local, &manifest))?;
+
+        // Chunks are compared by checksum since a plain split reuses names
+        let present = local
+            .chunks
+            .iter()
+            .map(|chunk| chunk.sha256.as_str())
+            .collect::<HashSet<_>>();
+        pending.retain(|chunk| !present.contains(chunk.sha256.as_str()));
+        println!(
+            "{} of {} chunks changed",
+            pending.len(),
+            manifest.chunks.len()
+        );
+    }
+
+    create_directories(output, &manifest.directories)?;
+
+    let names = options.metrics.time("fetch", || {
+        fetch_all(
+            pending,
+            |chunk| chunk.name.clone(),
+            options,
+            move |chunk| fetch_chunk(&context, chunk),
+        )
+    })?;
+    if !names.is_empty() {
+        return Err(RebuildError::ChunksFailed { names }.into());
+    }
+
+    options.metrics.time("restore", || {
+        restore_directories(output, &manifest.directories, options.preserve)
+    })?;
+
+    if options.delta {
+        manifest.save(&output.join(LOCAL_MANIFEST_NAME))?;
+    }
+
+    let directories = manifest
+        .directories
+        .iter()
+        .map(|directory| directory.path.as_path());
+    options
+        .metrics
+        .time("sync", || options.durability.finish(output, directories))?;
+    Ok(())
+}
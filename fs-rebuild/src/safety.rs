@@ -0,0 +1,231 @@
+use std::collections::HashSet;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::Result;
+use tar::{Entry, EntryType};
+
+use crate::compression::Compression;
+use crate::error::RebuildError;
+use crate::indexed::{IndexEntry, Kind};
+use crate::manifest::Manifest;
+
+/// Bounds on what a rebuild accepts from its source, which may not be
+/// trustworthy.
+#[derive(Clone, Copy)]
+pub struct Limits {
+    pub max_bytes: u64,
+    pub max_entries: u64,
+    pub max_window_log: u32,
+}
+
+/// Paths from the source must stay inside the output: relative, and without
+/// any `..` that could climb out of it.
+pub fn check_path(path: &Path) -> Result<(), RebuildError> {
+    let mut components = path.components();
+    let safe = components
+        .clone()
+        .any(|component| matches!(component, Component::Normal(_)))
+        && components
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if !safe {
+        return Err(RebuildError::UnsafePath {
+            path: path.to_path_buf(),
+        });
+    }
+    Ok(())
+}
+
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect()
+}
+
+/// The symlinks accepted so far from a manifest, index or chunk. Where a
+/// `..` follows one of them, where it leads depends on that symlink's own
+/// target, so it's refused whichever of the two came first.
+#[derive(Clone, Default)]
+pub struct Links {
+    paths: HashSet<PathBuf>,
+    /// Every directory an accepted target climbed out of with `..`.
+    climbed: HashSet<PathBuf>,
+}
+
+impl Links {
+    /// Resolves `target` from the directory holding the symlink at `path`,
+    /// which must already be safe, without touching the filesystem, and
+    /// refuses it if it ever leaves the output.
+    pub fn check(&mut self, path: &Path, target: &Path) -> Result<(), RebuildError> {
+        let escapes = || RebuildError::LinkEscapes {
+            path: path.to_path_buf(),
+            target: target.to_path_buf(),
+        };
+
+        let path = normalize(path);
+        if self.climbed.contains(&path) {
+            return Err(escapes());
+        }
+
+        let mut resolved = path.clone();
+        resolved.pop();
+        let mut through_link = resolved
+            .ancestors()
+            .any(|ancestor| self.paths.contains(ancestor));
+        let mut climbed = vec![];
+        for component in target.components() {
+            match component {
+                Component::Normal(name) => {
+                    resolved.push(name);
+                    through_link |= self.paths.contains(&resolved);
+                }
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    climbed.extend(resolved.ancestors().map(Path::to_path_buf));
+                    if through_link || !resolved.pop() {
+                        return Err(escapes());
+                    }
+                }
+                Component::RootDir | Component::Prefix(_) => return Err(escapes()),
+            }
+        }
+
+        self.climbed.extend(climbed);
+        self.paths.insert(path);
+        Ok(())
+    }
+}
+
+/// Refuses `path` unless the directory holding it, with every symlink on the
+/// way resolved, is inside `output`, so whatever is written there stays in
+/// the output too. That directory has to exist.
+pub fn check_beneath(output: &Path, path: &Path) -> io::Result<()> {
+    let parent = path.parent().unwrap_or(output);
+    if !parent.canonicalize()?.starts_with(output.canonicalize()?) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{:?} is outside of {:?}", parent, output),
+        ));
+    }
+    Ok(())
+}
+
+pub fn check_compression(compression: &Compression, limits: &Limits) -> Result<(), RebuildError> {
+    match compression.effective_window_log() {
+        Some(window_log) if window_log > limits.max_window_log => {
+            Err(RebuildError::WindowTooLarge {
+                window_log,
+                limit: limits.max_window_log,
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Checks every name and path in a manifest before anything is fetched.
+/// Returns its symlinks, for chunks to check theirs against.
+pub fn check_manifest(manifest: &Manifest, limits: &Limits) -> Result<Links, RebuildError> {
+    check_compression(&manifest.compression, limits)?;
+    if let Some(dictionary) = &manifest.compression.dictionary {
+        check_path(Path::new(&dictionary.name))?;
+    }
+
+    for path in manifest.entries() {
+        check_path(path)?;
+    }
+    let files = manifest.chunks.iter().flat_map(|chunk| chunk.files.iter());
+    if files
+        .map(|file| file.size)
+        .any(|size| size > limits.max_bytes)
+    {
+        return Err(RebuildError::TooManyBytes {
+            limit: limits.max_bytes,
+        });
+    }
+    let mut links = Links::default();
+    for chunk in manifest.chunks.iter() {
+        check_path(Path::new(&chunk.name))?;
+        for link in chunk.links.iter() {
+            links.check(&link.path, &link.target)?;
+        }
+    }
+    for directory in manifest.directories.iter() {
+        check_path(&directory.path)?;
+    }
+    Ok(links)
+}
+
+/// Chunks only hold what split writes, so anything else in one, hard links
+/// and device files included, is refused. Symlinks are checked along with
+/// the `links` accepted before them.
+pub fn check_entry<R: Read>(entry: &Entry<R>, links: &mut Links) -> Result<()> {
+    let path = entry.path()?.into_owned();
+    check_path(&path)?;
+
+    match entry.header().entry_type() {
+        EntryType::Regular | EntryType::Continuous | EntryType::Directory => Ok(()),
+        EntryType::Symlink => match entry.link_name()? {
+            Some(target) => Ok(links.check(&path, &target)?),
+            None => Err(RebuildError::UnsafePath { path }.into()),
+        },
+        kind => Err(RebuildError::UnsupportedEntry {
+            path,
+            kind: format!("{:?}", kind),
+        }
+        .into()),
+    }
+}
+
+/// The same checks as `check_entry`, for an entry of an indexed chunk.
+pub fn check_index_entry(entry: &IndexEntry, links: &mut Links) -> Result<(), RebuildError> {
+    check_path(&entry.path)?;
+
+    match (entry.kind, &entry.target) {
+        (Kind::File, None) => Ok(()),
+        (Kind::Symlink, Some(target)) => links.check(&entry.path, target),
+        _ => Err(RebuildError::UnsafePath {
+            path: entry.path.clone(),
+        }),
+    }
+}
+
+/// What has been unpacked so far, shared by every chunk of a rebuild.
+pub struct Budget {
+    limits: Limits,
+    bytes: AtomicU64,
+    entries: AtomicU64,
+}
+
+impl Budget {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            bytes: AtomicU64::new(0),
+            entries: AtomicU64::new(0),
+        }
+    }
+
+    /// Accounts for one more entry of `size` bytes.
+    pub fn charge(&self, size: u64) -> Result<(), RebuildError> {
+        let bytes = self.bytes.fetch_add(size, Ordering::Relaxed) + size;
+        let entries = self.entries.fetch_add(1, Ordering::Relaxed) + 1;
+        if bytes > self.limits.max_bytes {
+            return Err(RebuildError::TooManyBytes {
+                limit: self.limits.max_bytes,
+            });
+        }
+        if entries > self.limits.max_entries {
+            return Err(RebuildError::TooManyEntries {
+                limit: self.limits.max_entries,
+            });
+        }
+        Ok(())
+    }
+
+    /// Gives back what a failed chunk charged, since it will be fetched again.
+    pub fn release(&self, bytes: u64, entries: u64) {
+        self.bytes.fetch_sub(bytes, Ordering::Relaxed);
+        self.entries.fetch_sub(entries, Ordering::Relaxed);
+    }
+}
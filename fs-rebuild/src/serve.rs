@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+
+/// Idle keep-alive connections are closed after this long.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct Request {
+    method: String,
+    path: String,
+    headers: HashMap<String, String>,
+}
+
+impl Request {
+    fn read<R: BufRead>(reader: &mut R) -> io::Result<Option<Self>> {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        let mut parts = line.split_whitespace();
+        let method = parts.next().unwrap_or_default().to_string();
+        let target = parts.next().unwrap_or_default();
+        let path = target.split('?').next().unwrap_or_default().to_string();
+
+        let mut headers = HashMap::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+            }
+        }
+
+        Ok(Some(Self {
+            method,
+            path,
+            headers,
+        }))
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+
+    fn keep_alive(&self) -> bool {
+        !matches!(self.header("connection"), Some(value) if value.eq_ignore_ascii_case("close"))
+    }
+}
+
+/// Parses a single `bytes=` range against a body of `len` bytes into an
+/// inclusive `(start, end)`. `Err` means the range can't be satisfied.
+fn parse_range(range: &str, len: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = range.strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        // Multipart ranges aren't supported, fall back to the whole body
+        return None;
+    }
+
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix = suffix.parse::<u64>().ok()?;
+            (len.saturating_sub(suffix), len.checked_sub(1)?)
+        }
+        (start, "") => (start.parse().ok()?, len.saturating_sub(1)),
+        (start, end) => (
+            start.parse().ok()?,
+            end.parse::<u64>().ok()?.min(len.saturating_sub(1)),
+        ),
+    };
+
+    if start > end || start >= len {
+        return Some(Err(()));
+    }
+    Some(Ok((start, end)))
+}
+
+fn resolve(root: &Path, path: &str) -> Option<PathBuf> {
+    let relative = Path::new(path.trim_start_matches('/'));
+    if !relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return None;
+    }
+    Some(root.join(relative))
+}
+
+fn write_head(stream: &mut TcpStream, status: &str, headers: &[(&str, String)]) -> io::Result<()> {
+    let mut head = format!("HTTP/1.1 {}\r\n", status);
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())
+}
+
+fn respond(root: &Path, request: &Request, stream: &mut TcpStream) -> io::Result<()> {
+    let connection = if request.keep_alive() {
+        "keep-alive"
+    } else {
+        "close"
+    };
+
+    if request.method != "GET" && request.method != "HEAD" {
+        return write_head(
+            stream,
+            "405 Method Not Allowed",
+            &[
+                ("Allow", "GET, HEAD".to_string()),
+                ("Content-Length", "0".to_string()),
+                ("Connection", connection.to_string()),
+            ],
+        );
+    }
+
+    let file = resolve(root, &request.path)
+        .and_then(|path| File::open(path).ok())
+        .and_then(|file| file.metadata().ok().map(|meta| (file, meta)))
+        .filter(|(_, meta)| meta.is_file());
+
+    let (mut file, meta) = match file {
+        Some(found) => found,
+        None => {
+            return write_head(
+                stream,
+                "404 Not Found",
+                &[
+                    ("Content-Length", "0".to_string()),
+                    ("Connection", connection.to_string()),
+                ],
+            )
+        }
+    };
+
+    let len = meta.len();
+    let etag = format!("\"{:x}-{:x}\"", meta.mtime(), len);
+
+    if request.header("if-none-match") == Some(etag.as_str()) {
+        return write_head(
+            stream,
+            "304 Not Modified",
+            &[("ETag", etag), ("Connection", connection.to_string())],
+        );
+    }
+
+    // A range for a different version of the file than the client expects
+    // is ignored and the whole file is sent instead
+    let range = match request.header("if-range") {
+        Some(if_range) if if_range != etag => None,
+        _ => request
+            .header("range")
+            .and_then(|range| parse_range(range, len)),
+    };
+
+    let (status, start, end) = match range {
+        None => ("200 OK", 0, len),
+        Some(Ok((start, end))) => ("206 Partial Content", start, end + 1),
+        Some(Err(())) => {
+            return write_head(
+                stream,
+                "416 Range Not Satisfiable",
+                &[
+                    ("Content-Range", format!("bytes */{}", len)),
+                    ("Content-Length", "0".to_string()),
+                    ("Connection", connection.to_string()),
+                ],
+            )
+        }
+    };
+
+    let mut headers = vec![
+        ("Content-Type", "application/octet-stream".to_string()),
+        ("Content-Length", (end - start).to_string()),
+        ("Accept-Ranges", "bytes".to_string()),
+        ("ETag", etag),
+        ("Connection", connection.to_string()),
+    ];
+    if range.is_some() {
+        headers.push((
+            "Content-Range",
+            format!("bytes {}-{}/{}", start, end.saturating_sub(1), len),
+        ));
+    }
+    write_head(stream, status, &headers)?;
+
+    if request.method == "GET" {
+        file.seek(SeekFrom::Start(start))?;
+        // Copying from a file to a socket uses sendfile on Linux, so the
+        // body never passes through userspace
+        io::copy(&mut file.take(end - start), stream)?;
+    }
+
+    Ok(())
+}
+
+fn handle(root: &Path, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    loop {
+        let request = match Request::read(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Ok(())
+            }
+            Err(error) => return Err(error),
+        };
+
+        respond(root, &request, &mut stream)?;
+        if !request.keep_alive() {
+            return Ok(());
+        }
+    }
+}
+
+pub fn serve(dir: &Path, listen: &str) -> Result<()> {
+    let listener = TcpListener::bind(listen)?;
+    println!("serving {:?} on http://{}", dir, listener.local_addr()?);
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let root = dir.to_path_buf();
+        thread::spawn(move || {
+            if let Err(error) = handle(&root, stream) {
+                eprintln!("connection failed: {}", error);
+            }
+        });
+    }
+
+    Ok(())
+}
@@ -0,0 +1,95 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer};
+use rand::rngs::OsRng;
+
+use crate::error::RebuildError;
+use crate::source::ChunkSource;
+
+/// Signatures are stored next to what they sign, under the same name with
+/// this appended.
+const SIGNATURE_SUFFIX: &str = ".sig";
+
+fn read_base64(path: &Path) -> Result<Vec<u8>> {
+    let text = fs::read_to_string(path)?;
+    STANDARD
+        .decode(text.trim())
+        .map_err(|error| anyhow!("{:?} isn't valid base64: {}", path, error))
+}
+
+/// Writes a new secret key and its public key, both base64 encoded.
+pub fn keygen(secret_key: &Path, public_key: &Path) -> Result<()> {
+    let keypair = Keypair::generate(&mut OsRng);
+    // Only its owner may read the secret key, whatever the umask
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(secret_key)?
+        .write_all(STANDARD.encode(keypair.secret.as_bytes()).as_bytes())?;
+    fs::write(public_key, STANDARD.encode(keypair.public.as_bytes()))?;
+    println!("wrote {:?} and {:?}", secret_key, public_key);
+    Ok(())
+}
+
+pub fn read_secret_key(path: &Path) -> Result<Keypair> {
+    let secret = SecretKey::from_bytes(&read_base64(path)?)?;
+    let public = PublicKey::from(&secret);
+    Ok(Keypair { secret, public })
+}
+
+pub fn read_public_key(path: &Path) -> Result<PublicKey> {
+    Ok(PublicKey::from_bytes(&read_base64(path)?)?)
+}
+
+/// Signs the file at `path`, the signature ending up next to it.
+pub fn sign_file(keypair: &Keypair, path: &Path) -> Result<()> {
+    let signature = keypair.sign(&fs::read(path)?);
+    let mut signature_path = path.as_os_str().to_owned();
+    signature_path.push(SIGNATURE_SUFFIX);
+    fs::write(signature_path, STANDARD.encode(signature.to_bytes()))?;
+    Ok(())
+}
+
+/// Fetches `name` and, with a public key, its signature, failing unless the
+/// signature is there and matches. Manifests and indexes hold the checksum
+/// of everything else, so verifying them covers the whole tree: a chunk
+/// tampered with fails its checksum, and staging keeps what it unpacked from
+/// ever reaching the output. Rebuilding in place, with `--in-place` or
+/// `--delta`, has no staging, so what a tampered chunk held is already in
+/// the output by the time the rebuild fails.
+pub fn get_verified(
+    source: &dyn ChunkSource,
+    name: &str,
+    public_key: Option<&PublicKey>,
+) -> Result<Vec<u8>> {
+    let bytes = source.get(name)?;
+    let public_key = match public_key {
+        Some(public_key) => public_key,
+        None => return Ok(bytes),
+    };
+
+    let missing = || RebuildError::MissingSignature {
+        name: name.to_string(),
+    };
+    let encoded = source
+        .get(&format!("{}{}", name, SIGNATURE_SUFFIX))
+        .map_err(|_| missing())?;
+    let decoded = STANDARD
+        .decode(String::from_utf8_lossy(&encoded).trim())
+        .map_err(|_| missing())?;
+    let signature = Signature::from_bytes(&decoded).map_err(|_| missing())?;
+
+    public_key
+        .verify_strict(&bytes, &signature)
+        .map_err(|_| RebuildError::BadSignature {
+            name: name.to_string(),
+        })?;
+    Ok(bytes)
+}
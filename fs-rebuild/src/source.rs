@@ -0,0 +1,88 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+
+use crate::fetch::Fetcher;
+
+/// Where rebuild reads the manifest, dictionary and chunks from. Names are
+/// relative to the root of a split output directory.
+pub trait ChunkSource: Send + Sync {
+    fn open(&self, name: &str) -> io::Result<Box<dyn Read + Send>>;
+
+    fn get(&self, name: &str) -> io::Result<Vec<u8>> {
+        let mut bytes = vec![];
+        self.open(name)?.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Reads `len` bytes of `name` starting at `offset`.
+    fn read_range(&self, name: &str, offset: u64, len: u64) -> io::Result<Vec<u8>>;
+}
+
+/// Chunks served over HTTP, by `fs-rebuild serve` or any static file server.
+pub struct HttpSource {
+    base: String,
+    fetcher: Fetcher,
+}
+
+impl HttpSource {
+    pub fn new(base: &str, fetcher: Fetcher) -> Self {
+        Self {
+            base: base.trim_end_matches('/').to_string(),
+            fetcher,
+        }
+    }
+}
+
+impl ChunkSource for HttpSource {
+    fn open(&self, name: &str) -> io::Result<Box<dyn Read + Send>> {
+        let body = self.fetcher.open(&format!("{}/{}", self.base, name))?;
+        Ok(Box::new(body))
+    }
+
+    fn read_range(&self, name: &str, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        let url = format!("{}/{}", self.base, name);
+        self.fetcher.read_range(&url, offset, len)
+    }
+}
+
+/// Chunks in a local directory, such as a mounted volume or a cache.
+pub struct DirSource {
+    root: PathBuf,
+}
+
+impl DirSource {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+}
+
+impl ChunkSource for DirSource {
+    fn open(&self, name: &str) -> io::Result<Box<dyn Read + Send>> {
+        let file = File::open(self.root.join(name))?;
+        Ok(Box::new(file))
+    }
+
+    fn read_range(&self, name: &str, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        let mut file = File::open(self.root.join(name))?;
+        file.seek(SeekFrom::Start(offset))?;
+        // Reads what's there rather than allocating whatever the index says
+        let mut bytes = vec![];
+        file.take(len).read_to_end(&mut bytes)?;
+        if (bytes.len() as u64) < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(bytes)
+    }
+}
+
+/// Picks a source from `location`: `http://` and `https://` URLs are fetched
+/// with `fetcher`, `file://` URLs and anything else are read as a directory.
+pub fn from_location(location: &str, fetcher: Fetcher) -> Box<dyn ChunkSource> {
+    if location.starts_with("http://") || location.starts_with("https://") {
+        return Box::new(HttpSource::new(location, fetcher));
+    }
+
+    let path = location.strip_prefix("file://").unwrap_or(location);
+    Box::new(DirSource::new(PathBuf::from(path)))
+}
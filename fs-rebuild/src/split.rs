@@ -0,0 +1,516 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use anyhow::{anyhow, Result};
+use ed25519_dalek::Keypair;
+use tar::{Builder, Header};
+use walkdir::WalkDir;
+
+use crate::balance::{self, Balance, Report, Target};
+use crate::compression::{Codec, Compression, DictionaryEntry, Encoder, DICTIONARY_NAME};
+use crate::hash::{sha256_file, sha256_hex, HashReader, HashWriter};
+use crate::indexed::IndexWriter;
+use crate::manifest::{
+    ChunkEntry, Container, DirectoryEntry, FileEntry, LinkEntry, Manifest, MANIFEST_NAME,
+};
+use crate::signing;
+
+/// Only files up to this size are used as dictionary training samples, large
+/// files compress well on their own.
+const MAX_SAMPLE_SIZE: u64 = 128 * 1024;
+
+pub struct SplitOptions {
+    pub balance: Balance,
+    pub container: Container,
+    pub compression: Compression,
+    pub jobs: usize,
+    pub dictionary: Option<PathBuf>,
+    pub train_dictionary: Option<usize>,
+    pub incremental: bool,
+    pub signing_key: Option<Keypair>,
+}
+
+impl SplitOptions {
+    /// Workers compressing at once, one per core unless set.
+    pub fn jobs(&self) -> io::Result<usize> {
+        match self.jobs {
+            0 => Ok(thread::available_parallelism()?.get()),
+            jobs => Ok(jobs),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct FileMeta {
+    path: PathBuf,
+    size: u64,
+    is_symlink: bool,
+}
+
+impl FileMeta {
+    fn new(path: PathBuf, size: u64, is_symlink: bool) -> Self {
+        Self {
+            path,
+            size,
+            is_symlink,
+        }
+    }
+}
+
+/// Writes the entries of a chunk in one of the containers.
+enum ChunkWriter<'a> {
+    Tar(Builder<Encoder<HashWriter<fs::File>>>),
+    Indexed(IndexWriter<'a, HashWriter<fs::File>>),
+}
+
+impl<'a> ChunkWriter<'a> {
+    fn new(
+        file: fs::File,
+        container: Container,
+        compression: &'a Compression,
+        dictionary: &'a [u8],
+    ) -> io::Result<Self> {
+        let file = HashWriter::new(file);
+        match container {
+            Container::Tar => Ok(ChunkWriter::Tar(Builder::new(
+                compression.encoder(file, dictionary)?,
+            ))),
+            Container::Indexed => Ok(ChunkWriter::Indexed(IndexWriter::new(
+                file,
+                compression,
+                dictionary,
+            ))),
+        }
+    }
+
+    fn append_link(&mut self, path: &Path, meta: &fs::Metadata, target: &Path) -> io::Result<()> {
+        match self {
+            ChunkWriter::Tar(archive) => {
+                let mut header = Header::new_gnu();
+                header.set_metadata(meta);
+                header.set_size(0);
+                archive.append_link(&mut header, path, target)
+            }
+            ChunkWriter::Indexed(index) => index.append_link(path, meta, target),
+        }
+    }
+
+    fn append_file<R: Read>(
+        &mut self,
+        path: &Path,
+        meta: &fs::Metadata,
+        reader: &mut R,
+    ) -> io::Result<()> {
+        match self {
+            ChunkWriter::Tar(archive) => {
+                let mut header = Header::new_gnu();
+                header.set_metadata(meta);
+                archive.append_data(&mut header, path, reader)
+            }
+            ChunkWriter::Indexed(index) => index.append_file(path, meta, reader),
+        }
+    }
+
+    fn finish(self) -> io::Result<HashWriter<fs::File>> {
+        match self {
+            ChunkWriter::Tar(archive) => archive.into_inner()?.finish(),
+            ChunkWriter::Indexed(index) => index.finish(),
+        }
+    }
+}
+
+struct OutputChunk(Vec<FileMeta>);
+
+impl OutputChunk {
+    fn write(
+        &self,
+        prefix: &Path,
+        output: &Path,
+        name: &str,
+        container: Container,
+        compression: &Compression,
+        dictionary: &[u8],
+    ) -> Result<ChunkEntry> {
+        let file = fs::File::create(output.join(name))?;
+        let mut archive = ChunkWriter::new(file, container, compression, dictionary)?;
+
+        println!("writing {} files to {}", self.0.len(), name);
+
+        let mut files = Vec::with_capacity(self.0.len());
+        let mut links = vec![];
+        for meta in self.0.iter() {
+            let path = meta.path.strip_prefix(prefix)?;
+
+            if meta.is_symlink {
+                let target = fs::read_link(&meta.path)?;
+                archive.append_link(path, &fs::symlink_metadata(&meta.path)?, &target)?;
+
+                links.push(LinkEntry {
+                    path: path.to_path_buf(),
+                    target,
+                });
+                continue;
+            }
+
+            let file = fs::File::open(&meta.path)?;
+            let metadata = file.metadata()?;
+            let mut reader = HashReader::new(file);
+            archive.append_file(path, &metadata, &mut reader)?;
+
+            let (_, sha256, size) = reader.finish();
+            files.push(FileEntry {
+                path: path.to_path_buf(),
+                size,
+                sha256,
+                mode: metadata.mode(),
+                mtime: metadata.mtime(),
+            });
+        }
+
+        let (_, sha256, size) = archive.finish()?.finish();
+
+        Ok(ChunkEntry {
+            name: name.to_string(),
+            size,
+            file_count: files.len(),
+            sha256,
+            files,
+            links,
+        })
+    }
+
+    /// Whether `previous` holds exactly this chunk's entries with the same
+    /// contents, modes and mtimes, in which case it can be reused as is.
+    fn matches(&self, prefix: &Path, previous: &ChunkEntry) -> Result<bool> {
+        if self.0.len() != previous.files.len() + previous.links.len() {
+            return Ok(false);
+        }
+
+        let files = previous
+            .files
+            .iter()
+            .map(|file| (file.path.as_path(), file))
+            .collect::<HashMap<_, _>>();
+        let links = previous
+            .links
+            .iter()
+            .map(|link| (link.path.as_path(), link.target.as_path()))
+            .collect::<HashMap<_, _>>();
+
+        for meta in self.0.iter() {
+            let path = meta.path.strip_prefix(prefix)?;
+
+            let same = if meta.is_symlink {
+                links.get(path).copied() == Some(fs::read_link(&meta.path)?.as_path())
+            } else {
+                match files.get(path) {
+                    Some(file) => {
+                        let metadata = fs::metadata(&meta.path)?;
+                        file.size == meta.size
+                            && file.mode == metadata.mode()
+                            && file.mtime == metadata.mtime()
+                            && file.sha256 == sha256_file(&meta.path)?
+                    }
+                    None => false,
+                }
+            };
+            if !same {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+struct OutputChunks {
+    prefix: PathBuf,
+    chunks: Vec<OutputChunk>,
+    directories: Vec<DirectoryEntry>,
+    /// Chunks of the previous split at the same index, reused when unchanged.
+    previous: Vec<Option<ChunkEntry>>,
+    /// Names new chunks after their checksum so they never replace a file
+    /// that clients of the previous manifest may still fetch.
+    versioned: bool,
+    container: Container,
+}
+
+impl OutputChunks {
+    fn train_dictionary(&self, max_size: usize) -> Result<Vec<u8>> {
+        // zstd suggests samples totalling around 100 times the dictionary size
+        let budget = max_size as u64 * 100;
+        let mut total = 0;
+
+        let samples = self
+            .chunks
+            .iter()
+            .flat_map(|chunk| chunk.0.iter())
+            .filter(|meta| !meta.is_symlink && meta.size <= MAX_SAMPLE_SIZE)
+            .take_while(|meta| {
+                total += meta.size;
+                total <= budget
+            })
+            .map(|meta| meta.path.as_path())
+            .collect::<Vec<&Path>>();
+
+        println!(
+            "training {} byte dictionary from {} files",
+            max_size,
+            samples.len()
+        );
+        Ok(zstd::dict::from_files(samples, max_size)?)
+    }
+
+    fn write_chunk(
+        &self,
+        chunk: &OutputChunk,
+        output: &Path,
+        idx: usize,
+        compression: &Compression,
+        dictionary: &[u8],
+    ) -> Result<ChunkEntry> {
+        if let Some(previous) = self.previous.get(idx - 1).and_then(Option::as_ref) {
+            if chunk.matches(&self.prefix, previous)? {
+                println!("reusing {}", previous.name);
+                return Ok(previous.clone());
+            }
+        }
+
+        let extension = self.container.extension(compression.codec);
+        let write = |name: &str| {
+            let container = self.container;
+            chunk.write(
+                &self.prefix,
+                output,
+                name,
+                container,
+                compression,
+                dictionary,
+            )
+        };
+        if !self.versioned {
+            return write(&format!("{}.{}", idx, extension));
+        }
+
+        let partial = format!("{}.{}.partial", idx, extension);
+        let mut entry = write(&partial)?;
+        entry.name = format!("{}-{}.{}", idx, &entry.sha256[..12], extension);
+        fs::rename(output.join(&partial), output.join(&entry.name))?;
+        Ok(entry)
+    }
+
+    /// Compresses up to `jobs` chunks at once, each worker picking the next
+    /// chunk nobody has started on as soon as it's done with its last one.
+    fn write(
+        &self,
+        output: &Path,
+        compression: Compression,
+        dictionary: &[u8],
+        jobs: usize,
+    ) -> Result<()> {
+        let next = AtomicUsize::new(0);
+        let jobs = jobs.clamp(1, self.chunks.len().max(1));
+
+        let written = thread::scope(|scope| {
+            let workers = (0..jobs)
+                .map(|_| {
+                    scope.spawn(|| -> Result<Vec<(usize, ChunkEntry)>> {
+                        let mut written = vec![];
+                        loop {
+                            let idx = next.fetch_add(1, Ordering::Relaxed);
+                            let chunk = match self.chunks.get(idx) {
+                                Some(chunk) => chunk,
+                                None => return Ok(written),
+                            };
+                            let entry =
+                                self.write_chunk(chunk, output, idx + 1, &compression, dictionary)?;
+                            written.push((idx, entry));
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            workers
+                .into_iter()
+                .map(|worker| worker.join().unwrap())
+                .collect::<Result<Vec<_>>>()
+        })?;
+
+        let mut written = written.into_iter().flatten().collect::<Vec<_>>();
+        written.sort_by_key(|(idx, _)| *idx);
+
+        let manifest = Manifest {
+            chunks: written.into_iter().map(|(_, entry)| entry).collect(),
+            container: self.container,
+            compression,
+            directories: self.directories.clone(),
+        };
+
+        let sizes = manifest.chunks.iter().map(|chunk| chunk.size).collect();
+        println!("written {}", Report::new(sizes));
+        manifest.write(output)
+    }
+}
+
+/// Regular files and symlinks are spread over the chunks, while directories
+/// are only listed in the manifest so rebuild can create empty ones and
+/// restore their metadata once every chunk has been unpacked into them.
+///
+/// Entries listed in a `previous` manifest stay in the chunk they were in, and
+/// only new ones are balanced, so small changes to the input leave most
+/// chunks untouched.
+fn build_output_chunks(
+    input: &Path,
+    balance: &Balance,
+    previous: Option<&Manifest>,
+) -> Result<OutputChunks> {
+    let mut files = vec![];
+    let mut directories = vec![];
+
+    println!("reading from {:?}", input);
+    for entry_result in WalkDir::new(input).min_depth(1) {
+        let entry = entry_result?;
+        let meta = entry.metadata()?;
+        if meta.is_dir() {
+            let path = entry.path().strip_prefix(input)?.to_path_buf();
+            directories.push(DirectoryEntry::new(path, &meta));
+        } else if meta.is_file() {
+            files.push(FileMeta::new(entry.path().to_path_buf(), meta.len(), false))
+        } else if meta.file_type().is_symlink() {
+            files.push(FileMeta::new(entry.path().to_path_buf(), 0, true))
+        }
+    }
+
+    let weights = files
+        .iter()
+        .map(|meta| {
+            balance
+                .strategy
+                .weight(&meta.path, meta.size, meta.is_symlink)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let keys = files
+        .iter()
+        .map(|meta| Ok(balance.grouping.key(meta.path.strip_prefix(input)?)))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut previous_chunks = HashMap::new();
+    let mut target = balance.target;
+    if let Some(previous) = previous {
+        for (idx, chunk) in previous.chunks.iter().enumerate() {
+            let files = chunk.files.iter().map(|file| file.path.as_path());
+            let links = chunk.links.iter().map(|link| link.path.as_path());
+            previous_chunks.extend(files.chain(links).map(|path| (path, idx)));
+        }
+        // Changing the count would move entries between chunks
+        let count = previous.chunks.len();
+        if !matches!(target, Target::Chunks(chunks) if chunks == count) {
+            eprintln!(
+                "keeping the {} chunks of the previous split, ignoring --chunks and --target-bytes",
+                count
+            );
+        }
+        target = Target::Chunks(count);
+    }
+    let pinned = files
+        .iter()
+        .map(|meta| {
+            let path = meta.path.strip_prefix(input)?;
+            Ok(previous_chunks.get(path).copied())
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let assignment = balance::assign(&weights, &keys, &pinned, target);
+    println!(
+        "estimated {}",
+        Report::from_assignment(&assignment, &weights)
+    );
+
+    // Keeping each chunk in walk order puts files from the same directory
+    // next to each other, which compresses better than size order
+    let chunks = assignment
+        .into_iter()
+        .map(|mut indices| {
+            indices.sort_unstable();
+            OutputChunk(indices.into_iter().map(|idx| files[idx].clone()).collect())
+        })
+        .collect();
+
+    Ok(OutputChunks {
+        prefix: input.to_path_buf(),
+        chunks,
+        directories,
+        previous: vec![],
+        versioned: false,
+        container: Container::Tar,
+    })
+}
+
+pub fn split(input: &Path, output: &Path, options: &SplitOptions) -> Result<()> {
+    let previous = if options.incremental {
+        Manifest::load(&output.join(MANIFEST_NAME))?
+    } else {
+        None
+    };
+    if options.compression.codec != Codec::Zstd
+        && (options.dictionary.is_some() || options.train_dictionary.is_some())
+    {
+        return Err(anyhow!("dictionaries need the zstd codec"));
+    }
+
+    let mut chunks = build_output_chunks(input, &options.balance, previous.as_ref())?;
+    chunks.container = options.container;
+    fs::create_dir_all(output)?;
+
+    let previous_dictionary = previous
+        .as_ref()
+        .and_then(|previous| previous.compression.dictionary.as_ref());
+
+    // Retraining would change every chunk, so an incremental split keeps the
+    // dictionary it trained last time
+    let dictionary = match (
+        &options.dictionary,
+        options.train_dictionary,
+        previous_dictionary,
+    ) {
+        (Some(path), _, _) => fs::read(path)?,
+        (None, Some(_), Some(entry)) => fs::read(output.join(&entry.name))?,
+        (None, Some(max_size), None) => chunks.train_dictionary(max_size)?,
+        (None, None, _) => vec![],
+    };
+
+    let mut compression = options.compression.clone();
+    if !dictionary.is_empty() {
+        let sha256 = sha256_hex(&dictionary);
+        let name = match (previous_dictionary, &previous) {
+            (Some(entry), _) if entry.sha256 == sha256 => entry.name.clone(),
+            (_, Some(_)) => format!("dictionary-{}.zdict", &sha256[..12]),
+            (_, None) => DICTIONARY_NAME.to_string(),
+        };
+        fs::write(output.join(&name), &dictionary)?;
+        compression.dictionary = Some(DictionaryEntry { name, sha256 });
+    }
+
+    if let Some(previous) = previous {
+        chunks.versioned = true;
+        if previous.compression == compression && previous.container == options.container {
+            chunks.previous = previous.chunks.into_iter().map(Some).collect();
+        } else {
+            println!(
+                "compression or container changed since the previous split, rewriting every chunk"
+            );
+        }
+    }
+
+    chunks.write(output, compression, &dictionary, options.jobs()?)?;
+
+    if let Some(keypair) = &options.signing_key {
+        signing::sign_file(keypair, &output.join(MANIFEST_NAME))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,132 @@
+use std::ffi::{CString, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+
+use crate::durability;
+
+/// The staging directory sits next to `output` so it's on the same
+/// filesystem, which renaming one over the other requires. So does the
+/// previous tree when it's moved out of the way, under `suffix` instead.
+fn sibling_path(output: &Path, suffix: &str) -> Result<PathBuf> {
+    let name = output
+        .file_name()
+        .ok_or_else(|| anyhow!("{:?} has no name to stage it under", output))?;
+
+    let mut sibling = OsString::from(".");
+    sibling.push(name);
+    sibling.push(suffix);
+    Ok(output.with_file_name(sibling))
+}
+
+/// Gives the owner full access to every directory under `path`, parents
+/// first so they can be listed. A restored mode like 0555 would otherwise
+/// keep anyone but root from removing what's in them.
+fn make_writable(path: &Path) -> io::Result<()> {
+    let meta = fs::symlink_metadata(path)?;
+    if !meta.is_dir() {
+        return Ok(());
+    }
+    let mode = meta.permissions().mode();
+    if mode & 0o700 != 0o700 {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode | 0o700))?;
+    }
+    for entry in fs::read_dir(path)? {
+        make_writable(&entry?.path())?;
+    }
+    Ok(())
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match make_writable(path).and_then(|_| fs::remove_dir_all(path)) {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+        _ => Ok(()),
+    }
+}
+
+/// Atomically swaps two paths with `renameat2(RENAME_EXCHANGE)`.
+fn exchange(from: &Path, to: &Path) -> io::Result<()> {
+    let from = CString::new(from.as_os_str().as_bytes())?;
+    let to = CString::new(to.as_os_str().as_bytes())?;
+    let result = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            from.as_ptr(),
+            libc::AT_FDCWD,
+            to.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Swaps `staging` into place at `output`, leaving the previous tree at
+/// `staging`. Where the filesystem, or a sandbox in front of it, can't
+/// exchange them, `output` is renamed to `previous` and `staging` renamed
+/// after it, which leaves a moment without an `output`.
+fn swap(staging: &Path, output: &Path, previous: &Path) -> io::Result<()> {
+    match exchange(staging, output) {
+        Err(error) if matches!(error.raw_os_error(), Some(libc::EINVAL | libc::ENOSYS)) => {
+            eprintln!(
+                "can't exchange {:?} atomically, renaming it: {}",
+                output, error
+            );
+        }
+        result => return result,
+    }
+
+    fs::rename(output, previous)?;
+    if let Err(error) = fs::rename(staging, output) {
+        fs::rename(previous, output)?;
+        return Err(error);
+    }
+    fs::rename(previous, staging)
+}
+
+/// Runs `build` on a staging directory and swaps it into place at `output`
+/// once it succeeds, so `output` is only ever the previous tree or the whole
+/// new one. The staging directory is removed whether `build` or the swap
+/// fails or not, along with the previous tree. With `sync`, the swap itself
+/// is made durable.
+pub fn staged<F>(output: &Path, sync: bool, build: F) -> Result<()>
+where
+    F: FnOnce(&Path) -> Result<()>,
+{
+    let staging = sibling_path(output, ".staging")?;
+    let previous = sibling_path(output, ".previous")?;
+    // Left behind by a rebuild that was killed
+    remove_if_exists(&staging)?;
+    remove_if_exists(&previous)?;
+    fs::create_dir_all(&staging)?;
+
+    if let Err(error) = build(&staging) {
+        remove_if_exists(&staging)?;
+        return Err(error);
+    }
+
+    // Once swapped, the staging directory holds the previous tree
+    let swapped = if output.exists() {
+        swap(&staging, output, &previous)
+    } else {
+        fs::rename(&staging, output)
+    };
+    let removed = remove_if_exists(&staging);
+    swapped?;
+    removed?;
+
+    if sync {
+        // The swap only changed entries of the directory holding the output
+        let parent = output.parent().filter(|parent| parent != &Path::new(""));
+        durability::sync_path(parent.unwrap_or_else(|| Path::new(".")))?;
+    }
+
+    println!("swapped {:?} into place", output);
+    Ok(())
+}
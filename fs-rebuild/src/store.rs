@@ -0,0 +1,522 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{fchown, symlink, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use crate::compression::Compression;
+use crate::durability::Durability;
+use crate::error::RebuildError;
+use crate::fetch::Fetcher;
+use crate::hash::{sha256_file, HashReader, HashWriter};
+use crate::manifest::{DirectoryEntry, LinkEntry};
+use crate::metrics::{self, ChunkMetrics, Metrics, TimedReader};
+use crate::rebuild::{self, Preserve, RebuildOptions};
+use crate::safety::{self, Limits, Links};
+use crate::signing;
+use crate::source::{self, ChunkSource};
+use crate::split::SplitOptions;
+
+/// Each file is stored once, compressed on its own, under its sha256.
+const BLOB_DIR: &str = "blobs";
+const INDEX_DIR: &str = "indexes";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct IndexFile {
+    pub path: PathBuf,
+    pub size: u64,
+    pub sha256: String,
+    pub mode: u32,
+    pub mtime: i64,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// One version of a tree in the store. Files only point at blobs, so indexes
+/// stay small and versions share every file they have in common.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Index {
+    pub compression: Compression,
+    pub files: Vec<IndexFile>,
+    #[serde(default)]
+    pub links: Vec<LinkEntry>,
+    #[serde(default)]
+    pub directories: Vec<DirectoryEntry>,
+}
+
+/// Blobs are named after what it takes to decode them too, so versions
+/// stored with another codec or zstd window don't share them.
+fn blob_name(sha256: &str, compression: &Compression) -> String {
+    let mut name = format!("{}/{}/{}", BLOB_DIR, &sha256[..2], sha256);
+    if let Some(window_log) = compression.effective_window_log() {
+        name.push_str(&format!(".w{}", window_log));
+    }
+    if let Some(extension) = compression.codec.extension() {
+        name.push('.');
+        name.push_str(extension);
+    }
+    name
+}
+
+fn index_name(tag: &str) -> String {
+    format!("{}/{}.json", INDEX_DIR, tag)
+}
+
+/// The store counterpart of `safety::check_manifest`. Checksums name blobs,
+/// so they have to be actual sha256 hex digests too.
+fn check_index(index: &Index, limits: &Limits) -> Result<(), RebuildError> {
+    safety::check_compression(&index.compression, limits)?;
+
+    for file in index.files.iter() {
+        safety::check_path(&file.path)?;
+        let valid = file.sha256.len() == 64 && file.sha256.bytes().all(|b| b.is_ascii_hexdigit());
+        if !valid {
+            return Err(RebuildError::UnsafePath {
+                path: PathBuf::from(&file.sha256),
+            });
+        }
+    }
+    let mut links = Links::default();
+    for link in index.links.iter() {
+        safety::check_path(&link.path)?;
+        links.check(&link.path, &link.target)?;
+    }
+    for directory in index.directories.iter() {
+        safety::check_path(&directory.path)?;
+    }
+
+    let bytes = index.files.iter().map(|file| file.size).sum::<u64>();
+    if bytes > limits.max_bytes {
+        return Err(RebuildError::TooManyBytes {
+            limit: limits.max_bytes,
+        });
+    }
+    let entries = index.files.len() + index.links.len() + index.directories.len();
+    if entries as u64 > limits.max_entries {
+        return Err(RebuildError::TooManyEntries {
+            limit: limits.max_entries,
+        });
+    }
+    Ok(())
+}
+
+/// Adds `path` to the store unless a blob with the same contents is already
+/// there. Returns the file's sha256 and whether a new blob was written.
+/// `worker` keeps the partial blobs of files with identical contents stored
+/// at the same time apart.
+fn store_file(
+    output: &Path,
+    path: &Path,
+    compression: &Compression,
+    worker: usize,
+) -> Result<(String, bool)> {
+    let sha256 = sha256_file(path)?;
+    let blob = output.join(blob_name(&sha256, compression));
+    if blob.exists() {
+        return Ok((sha256, false));
+    }
+
+    fs::create_dir_all(blob.parent().unwrap())?;
+    let partial = blob.with_extension(format!("{}.partial", worker));
+    let mut encoder = compression.encoder(File::create(&partial)?, &[])?;
+    let mut reader = HashReader::new(File::open(path)?);
+    io::copy(&mut reader, &mut encoder)?;
+    encoder.finish()?;
+
+    let (_, actual, _) = reader.finish();
+    if actual != sha256 {
+        fs::remove_file(&partial)?;
+        return Err(anyhow!("{:?} changed while it was being stored", path));
+    }
+
+    fs::rename(&partial, &blob)?;
+    Ok((sha256, true))
+}
+
+/// Stores every file of `input` that isn't already in the store at `output`
+/// and writes the index of this version as `tag`.
+pub fn split(input: &Path, output: &Path, tag: &str, options: &SplitOptions) -> Result<()> {
+    let mut files = vec![];
+    let mut links = vec![];
+    let mut directories = vec![];
+
+    println!("reading from {:?}", input);
+    for entry_result in WalkDir::new(input).min_depth(1) {
+        let entry = entry_result?;
+        let meta = entry.metadata()?;
+        let path = entry.path().strip_prefix(input)?.to_path_buf();
+        if meta.is_dir() {
+            directories.push(DirectoryEntry::new(path, &meta));
+        } else if meta.is_file() {
+            files.push((path, meta));
+        } else if meta.file_type().is_symlink() {
+            let target = fs::read_link(entry.path())?;
+            links.push(LinkEntry { path, target });
+        }
+    }
+
+    let mut compression = options.compression.clone();
+    compression.dictionary = None;
+
+    let next = AtomicUsize::new(0);
+    let stored = AtomicUsize::new(0);
+    let jobs = options.jobs()?.clamp(1, files.len().max(1));
+
+    let indexed = thread::scope(|scope| {
+        let workers = (0..jobs)
+            .map(|worker| {
+                let (next, stored, files, compression) = (&next, &stored, &files, &compression);
+                scope.spawn(move || -> Result<Vec<(usize, IndexFile)>> {
+                    let mut indexed = vec![];
+                    loop {
+                        let idx = next.fetch_add(1, Ordering::Relaxed);
+                        let (path, meta) = match files.get(idx) {
+                            Some(file) => file,
+                            None => return Ok(indexed),
+                        };
+
+                        let (sha256, new) =
+                            store_file(output, &input.join(path), compression, worker)?;
+                        if new {
+                            stored.fetch_add(1, Ordering::Relaxed);
+                        }
+                        indexed.push((
+                            idx,
+                            IndexFile {
+                                path: path.clone(),
+                                size: meta.len(),
+                                sha256,
+                                mode: meta.mode(),
+                                mtime: meta.mtime(),
+                                uid: meta.uid(),
+                                gid: meta.gid(),
+                            },
+                        ));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap())
+            .collect::<Result<Vec<_>>>()
+    })?;
+
+    let mut indexed = indexed.into_iter().flatten().collect::<Vec<_>>();
+    indexed.sort_by_key(|(idx, _)| *idx);
+
+    let index = Index {
+        compression,
+        files: indexed.into_iter().map(|(_, file)| file).collect(),
+        links,
+        directories,
+    };
+
+    println!(
+        "stored {} new blobs for {} files",
+        stored.into_inner(),
+        index.files.len()
+    );
+
+    let path = output.join(index_name(tag));
+    fs::create_dir_all(path.parent().unwrap())?;
+    let partial = path.with_extension("json.partial");
+    serde_json::to_writer_pretty(File::create(&partial)?, &index)?;
+    fs::rename(&partial, &path)?;
+
+    if let Some(keypair) = &options.signing_key {
+        signing::sign_file(keypair, &path)?;
+    }
+    Ok(())
+}
+
+/// Everything shared by the blobs of one rebuild.
+struct Context {
+    output: PathBuf,
+    source: Box<dyn ChunkSource>,
+    compression: Compression,
+    cache: Option<PathBuf>,
+    preserve: Preserve,
+    max_window_log: u32,
+    metrics: Arc<Metrics>,
+    durability: Durability,
+}
+
+/// A blob on its way into the cache. Whatever is read from the source is
+/// written to the partial blob too, which only replaces the cached one once
+/// the blob decoded to its checksum.
+struct Filling {
+    source: Box<dyn Read + Send>,
+    partial: File,
+}
+
+impl Read for Filling {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.source.read(buf)?;
+        self.partial.write_all(&buf[..read])?;
+        Ok(read)
+    }
+}
+
+impl Context {
+    /// Where this process writes a blob before it goes into the cache, which
+    /// other rebuilds sharing the cache don't write to.
+    fn partial(&self, cached: &Path) -> PathBuf {
+        cached.with_extension(format!("{}.partial", process::id()))
+    }
+
+    /// Opens a blob from the cache, or from the source while filling the
+    /// cache with it when it's missing there, or straight from the source
+    /// without a cache. Returns the partial blob being filled, if any.
+    fn open(&self, name: &str) -> Result<(Box<dyn Read + Send>, Option<PathBuf>)> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return Ok((self.source.open(name)?, None)),
+        };
+
+        let cached = cache.join(name);
+        if cached.exists() {
+            return Ok((Box::new(File::open(cached)?), None));
+        }
+        fs::create_dir_all(cached.parent().unwrap())?;
+        let partial = self.partial(&cached);
+        let filling = Filling {
+            source: self.source.open(name)?,
+            partial: File::create(&partial)?,
+        };
+        Ok((Box::new(filling), Some(partial)))
+    }
+
+    /// Drops a blob that couldn't be rebuilt from the cache, along with what
+    /// this process fetched of it, so the next attempt fetches it again.
+    fn evict(&self, name: &str) -> io::Result<()> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return Ok(()),
+        };
+        let cached = cache.join(name);
+        remove_existing(&self.partial(&cached))?;
+        remove_existing(&cached)
+    }
+}
+
+/// Removes whatever an earlier rebuild left at `path`, so new files don't
+/// get written through an old symlink.
+fn remove_existing(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+        _ => Ok(()),
+    }
+}
+
+/// Creates the file at `path` inside `output`, never through a symlink.
+fn replace(output: &Path, path: &Path) -> io::Result<File> {
+    safety::check_beneath(output, path)?;
+    remove_existing(path)?;
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(path)
+}
+
+/// Goes through the handle the file was written with, which the mode it's
+/// given doesn't affect.
+fn restore_file(handle: &File, file: &IndexFile, preserve: Preserve) -> Result<()> {
+    if preserve.mtime && file.mtime >= 0 {
+        let mtime = UNIX_EPOCH + Duration::from_secs(file.mtime as u64);
+        handle.set_modified(mtime)?;
+    }
+
+    if preserve.ownership {
+        fchown(handle, Some(file.uid), Some(file.gid))?;
+    }
+
+    let mode = if preserve.permissions {
+        file.mode & 0o7777
+    } else {
+        file.mode & 0o777
+    };
+    handle.set_permissions(Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+fn fetch_blob(context: &Context, sha256: &str, files: &[IndexFile]) -> Result<()> {
+    let name = blob_name(sha256, &context.compression);
+    let mut timings = ChunkMetrics::start(&name);
+    let mut result = fetch_blob_timed(context, &name, sha256, files, &mut timings);
+    if result.is_err() {
+        // A cached blob can be corrupted in ways that fail before the
+        // checksum is compared, such as a garbled frame
+        result = result.and(context.evict(&name).map_err(Into::into));
+    }
+    context.metrics.record(timings, &result);
+    result
+}
+
+/// Decompresses a blob into the first of its files while checking its
+/// checksum, then copies it to the others. Decompression stops at the size
+/// the index gives, which the index limits were checked against. A blob
+/// fetched into the cache is only kept there once its checksum matched.
+fn fetch_blob_timed(
+    context: &Context,
+    name: &str,
+    sha256: &str,
+    files: &[IndexFile],
+    timings: &mut ChunkMetrics,
+) -> Result<()> {
+    let (body, partial) = context.open(name)?;
+    let body = TimedReader::new(body, timings.started());
+    let decoder = TimedReader::new(
+        context
+            .compression
+            .decoder(BufReader::new(body), &[], context.max_window_log)?,
+        timings.started(),
+    );
+
+    let started = Instant::now();
+    let first = context.output.join(&files[0].path);
+    let mut writer = HashWriter::new(replace(&context.output, &first)?);
+    let mut decoder = decoder.take(files[0].size);
+    io::copy(&mut decoder, &mut writer)?;
+    let (written, actual, _) = writer.finish();
+
+    let decoder = decoder.into_inner();
+    let decoded = decoder.timing();
+    let mut body = decoder.into_inner().finish();
+    timings.streamed(body.get_ref().timing(), decoded);
+
+    if actual != sha256 {
+        return Err(RebuildError::CorruptFile {
+            path: first,
+            expected: sha256.to_string(),
+            actual,
+        }
+        .into());
+    }
+
+    if let (Some(partial), Some(cache)) = (partial, &context.cache) {
+        // Whatever follows the decoded size belongs in the cache too
+        io::copy(&mut body, &mut io::sink())?;
+        drop(body);
+        fs::rename(partial, cache.join(name))?;
+    }
+
+    // Copies are read back through the first file's handle, since its mode
+    // may not let it be opened again
+    let mut synced = Duration::ZERO;
+    for (idx, file) in files.iter().enumerate() {
+        let copy = if idx == 0 {
+            None
+        } else {
+            let mut copy = replace(&context.output, &context.output.join(&file.path))?;
+            (&written).seek(SeekFrom::Start(0))?;
+            io::copy(&mut &written, &mut copy)?;
+            Some(copy)
+        };
+        let handle = copy.as_ref().unwrap_or(&written);
+        restore_file(handle, file, context.preserve)?;
+
+        if context.durability.per_file() {
+            let started = Instant::now();
+            handle.sync_all()?;
+            synced += started.elapsed();
+        }
+    }
+    timings.files = files.len() as u64;
+    timings.fsync_ms = metrics::millis(synced);
+    timings.unpack_ms = metrics::millis(started.elapsed().saturating_sub(decoded.elapsed + synced));
+    Ok(())
+}
+
+/// Rebuilds the version `tag` of the store at `location`. Only blobs missing
+/// from `cache` are fetched, and each is fetched once however many files
+/// share it.
+pub fn rebuild(
+    output: &Path,
+    location: &str,
+    tag: &str,
+    cache: Option<&Path>,
+    options: &RebuildOptions,
+) -> Result<()> {
+    let fetcher = Fetcher::new(options.retries, options.backoff);
+    let source = source::from_location(location, fetcher);
+    let index = options.metrics.time("manifest", || -> Result<Index> {
+        let bytes = signing::get_verified(
+            source.as_ref(),
+            &index_name(tag),
+            options.public_key.as_ref(),
+        )?;
+        let index = serde_json::from_slice(&bytes)?;
+        check_index(&index, &options.limits)?;
+        Ok(index)
+    })?;
+
+    let mut blobs = BTreeMap::<String, Vec<IndexFile>>::new();
+    for file in index.files.iter() {
+        blobs
+            .entry(file.sha256.clone())
+            .or_default()
+            .push(file.clone());
+    }
+    println!(
+        "rebuilding {} files from {} blobs",
+        index.files.len(),
+        blobs.len()
+    );
+
+    let context = Context {
+        output: output.to_path_buf(),
+        source,
+        compression: index.compression.clone(),
+        cache: cache.map(Path::to_path_buf),
+        preserve: options.preserve,
+        max_window_log: options.limits.max_window_log,
+        metrics: options.metrics.clone(),
+        durability: options.durability,
+    };
+
+    rebuild::forget_delta(output)?;
+    rebuild::create_directories(output, &index.directories)?;
+
+    let names = options.metrics.time("fetch", || {
+        rebuild::fetch_all(
+            blobs.into_iter().collect(),
+            |(sha256, _)| blob_name(sha256, &index.compression),
+            options,
+            move |(sha256, files)| fetch_blob(&context, sha256, files),
+        )
+    })?;
+    if !names.is_empty() {
+        return Err(RebuildError::ChunksFailed { names }.into());
+    }
+
+    options.metrics.time("restore", || {
+        for link in index.links.iter() {
+            let path = output.join(&link.path);
+            safety::check_beneath(output, &path)?;
+            remove_existing(&path)?;
+            symlink(&link.target, &path)?;
+        }
+        rebuild::restore_directories(output, &index.directories, options.preserve)
+    })?;
+
+    let directories = index
+        .directories
+        .iter()
+        .map(|directory| directory.path.as_path());
+    options
+        .metrics
+        .time("sync", || options.durability.finish(output, directories))?;
+    Ok(())
+}
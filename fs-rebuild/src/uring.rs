@@ -0,0 +1,250 @@
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use io_uring::{opcode, squeue, types, IoUring, Probe};
+use tar::{Entry, EntryType};
+
+use crate::pool::Metadata;
+use crate::rebuild::Preserve;
+
+/// Files up to this size are read into memory and written in batches, larger
+/// ones are left to tar, which streams them.
+const MAX_BATCHED_FILE: u64 = 1024 * 1024;
+
+/// A batch is written once it holds this many files or bytes.
+const BATCH_FILES: usize = 128;
+const BATCH_BYTES: usize = 16 * 1024 * 1024;
+
+/// Checks that the kernel has io_uring and every operation the writer uses.
+pub fn probe() -> io::Result<()> {
+    let ring = IoUring::new(BATCH_FILES as u32)?;
+    let mut probe = Probe::new();
+    ring.submitter().register_probe(&mut probe)?;
+
+    for code in [
+        opcode::OpenAt2::CODE,
+        opcode::Write::CODE,
+        opcode::Fsync::CODE,
+        opcode::Close::CODE,
+    ] {
+        if !probe.is_supported(code) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("io_uring doesn't support opcode {}", code),
+            ));
+        }
+    }
+    Ok(())
+}
+
+struct PendingFile {
+    path: CString,
+    data: Vec<u8>,
+    metadata: Metadata,
+}
+
+/// Writes small files from tar entries in batches, with one submission each
+/// to open, write, fsync if asked to, and close the whole batch. Files are
+/// opened relative to the output with `RESOLVE_BENEATH`, so no symlink can
+/// lead them out of it.
+pub struct UringWriter {
+    ring: IoUring,
+    output: File,
+    preserve: Preserve,
+    sync: bool,
+    synced: Duration,
+    pending: Vec<PendingFile>,
+    bytes: usize,
+}
+
+impl UringWriter {
+    pub fn new(output: &Path, preserve: Preserve, sync: bool) -> io::Result<Self> {
+        Ok(Self {
+            ring: IoUring::new(BATCH_FILES as u32)?,
+            output: File::open(output)?,
+            preserve,
+            sync,
+            synced: Duration::ZERO,
+            pending: vec![],
+            bytes: 0,
+        })
+    }
+
+    /// Whether `push` takes this entry, otherwise it has to go through tar
+    /// once the batch is flushed.
+    pub fn accepts<R: Read>(entry: &Entry<R>) -> bool {
+        matches!(
+            entry.header().entry_type(),
+            EntryType::Regular | EntryType::Continuous
+        ) && entry.size() <= MAX_BATCHED_FILE
+    }
+
+    pub fn push<R: Read>(&mut self, path: &Path, entry: &mut Entry<R>) -> io::Result<()> {
+        let mut file = PendingFile {
+            path: CString::new(path.as_os_str().as_bytes())?,
+            data: Vec::with_capacity(entry.size() as usize),
+            metadata: Metadata::from_header(entry.header())?,
+        };
+        entry.read_to_end(&mut file.data)?;
+
+        self.bytes += file.data.len();
+        self.pending.push(file);
+        if self.pending.len() == BATCH_FILES || self.bytes >= BATCH_BYTES {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Submits `entries` at once and returns each one's result, in order.
+    fn submit(&mut self, entries: Vec<squeue::Entry>) -> io::Result<Vec<i32>> {
+        let count = entries.len();
+        for (idx, entry) in entries.into_iter().enumerate() {
+            // Safe since every buffer an entry points to outlives the call
+            unsafe { self.ring.submission().push(&entry.user_data(idx as u64)) }
+                .map_err(|_| io::Error::other("io_uring queue is full"))?;
+        }
+        self.ring.submit_and_wait(count)?;
+
+        let mut results = vec![0; count];
+        for completion in self.ring.completion() {
+            results[completion.user_data() as usize] = completion.result();
+        }
+        Ok(results)
+    }
+
+    fn open_all(&mut self, indices: &[usize], how: &types::OpenHow) -> io::Result<Vec<i32>> {
+        let dirfd = types::Fd(self.output.as_raw_fd());
+        let entries = indices
+            .iter()
+            .map(|&idx| opcode::OpenAt2::new(dirfd, self.pending[idx].path.as_ptr(), how).build())
+            .collect();
+        self.submit(entries)
+    }
+
+    /// Opens every pending file, replacing what an earlier rebuild left
+    /// there if it's a symlink.
+    fn open(&mut self) -> io::Result<Vec<File>> {
+        let flags = libc::O_CREAT | libc::O_WRONLY | libc::O_TRUNC | libc::O_NOFOLLOW;
+        let how = types::OpenHow::new()
+            .flags((flags | libc::O_CLOEXEC) as u64)
+            .mode(0o600)
+            .resolve(libc::RESOLVE_BENEATH);
+
+        let all = (0..self.pending.len()).collect::<Vec<_>>();
+        let mut results = self.open_all(&all, &how)?;
+
+        let links = all
+            .into_iter()
+            .filter(|&idx| results[idx] == -libc::ELOOP)
+            .collect::<Vec<_>>();
+        if !links.is_empty() {
+            for &idx in links.iter() {
+                let path = self.pending[idx].path.as_ptr();
+                if unsafe { libc::unlinkat(self.output.as_raw_fd(), path, 0) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            for (idx, result) in links.iter().zip(self.open_all(&links, &how)?) {
+                results[*idx] = result;
+            }
+        }
+
+        // Wraps every opened file first, so they're all closed on an error
+        let files = results
+            .iter()
+            .filter(|&&result| result >= 0)
+            .map(|&fd| unsafe { File::from_raw_fd(fd) })
+            .collect::<Vec<_>>();
+        if let Some(&error) = results.iter().find(|&&result| result < 0) {
+            return Err(io::Error::from_raw_os_error(-error));
+        }
+        Ok(files)
+    }
+
+    /// Writes every pending file, finishing any short write synchronously.
+    fn write(&mut self, files: &[File]) -> io::Result<()> {
+        let entries = files
+            .iter()
+            .zip(self.pending.iter())
+            .map(|(file, pending)| {
+                let fd = types::Fd(file.as_raw_fd());
+                opcode::Write::new(fd, pending.data.as_ptr(), pending.data.len() as u32).build()
+            })
+            .collect();
+        let results = self.submit(entries)?;
+
+        for ((file, pending), result) in files.iter().zip(self.pending.iter()).zip(results) {
+            if result < 0 {
+                return Err(io::Error::from_raw_os_error(-result));
+            }
+            let written = result as usize;
+            file.write_all_at(&pending.data[written..], written as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Time spent waiting on fsync so far.
+    pub fn synced(&self) -> Duration {
+        self.synced
+    }
+
+    fn fsync(&mut self, files: &[File]) -> io::Result<()> {
+        let started = Instant::now();
+        let entries = files
+            .iter()
+            .map(|file| opcode::Fsync::new(types::Fd(file.as_raw_fd())).build())
+            .collect();
+
+        for result in self.submit(entries)? {
+            if result < 0 {
+                return Err(io::Error::from_raw_os_error(-result));
+            }
+        }
+        self.synced += started.elapsed();
+        Ok(())
+    }
+
+    fn close(&mut self, files: Vec<File>) -> io::Result<()> {
+        let fds = files
+            .into_iter()
+            .map(IntoRawFd::into_raw_fd)
+            .collect::<Vec<RawFd>>();
+        let entries = fds
+            .iter()
+            .map(|&fd| opcode::Close::new(types::Fd(fd)).build())
+            .collect();
+
+        for result in self.submit(entries)? {
+            if result < 0 {
+                return Err(io::Error::from_raw_os_error(-result));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let files = self.open()?;
+        self.write(&files)?;
+        for (file, pending) in files.iter().zip(self.pending.iter()) {
+            pending.metadata.restore(file, self.preserve)?;
+        }
+        if self.sync {
+            self.fsync(&files)?;
+        }
+        self.close(files)?;
+
+        self.pending.clear();
+        self.bytes = 0;
+        Ok(())
+    }
+}
@@ -0,0 +1,1397 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use reqwest::blocking::Client;
+use reqwest::header::{ETAG, IF_NONE_MATCH, RANGE};
+use reqwest::StatusCode;
+use sha2::{Digest, Sha256};
+use tar::{EntryType, Header};
+use walkdir::WalkDir;
+
+fn input_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("input")
+}
+
+fn fs_rebuild(args: &[&str]) {
+    let status = Command::new(env!("CARGO_BIN_EXE_fs-rebuild"))
+        .args(args)
+        .status()
+        .unwrap();
+    assert!(status.success(), "fs-rebuild {:?} failed", args);
+}
+
+/// A `fs-rebuild serve` process, killed when dropped.
+struct Server {
+    child: Child,
+    host: String,
+}
+
+impl Server {
+    fn start(dir: &Path) -> Self {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_fs-rebuild"))
+            .args(["serve", "--listen", "127.0.0.1:0", "--dir"])
+            .arg(dir)
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        let mut line = String::new();
+        BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        let host = line.trim().rsplit(' ').next().unwrap().to_string();
+
+        Self { child, host }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn assert_same_tree(expected: &Path, actual: &Path) {
+    let files = |root: &Path| {
+        WalkDir::new(root)
+            .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+            .into_iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.file_type().is_file())
+            .filter(|entry| entry.file_name() != ".fs-rebuild-manifest.json")
+            .map(|entry| {
+                let relative = entry.path().strip_prefix(root).unwrap().to_path_buf();
+                (relative, fs::read(entry.path()).unwrap())
+            })
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(files(expected), files(actual));
+}
+
+fn split_and_rebuild(input: &Path, chunks: &Path, output: &Path) {
+    fs_rebuild(&[
+        "split",
+        "--chunks",
+        "3",
+        "--input",
+        input.to_str().unwrap(),
+        "--output",
+        chunks.to_str().unwrap(),
+    ]);
+
+    let server = Server::start(chunks);
+    fs_rebuild(&[
+        "rebuild",
+        "--source",
+        &server.host,
+        "--output",
+        output.to_str().unwrap(),
+    ]);
+}
+
+#[test]
+fn split_then_rebuild_over_http() {
+    let chunks = tempfile::tempdir().unwrap();
+    let output = tempfile::tempdir().unwrap();
+
+    split_and_rebuild(&input_dir(), chunks.path(), output.path());
+
+    for idx in 1..=3 {
+        assert!(chunks.path().join(format!("{}.tar.zst", idx)).is_file());
+    }
+    assert_same_tree(&input_dir(), output.path());
+}
+
+#[test]
+fn split_then_rebuild_from_directory() {
+    let chunks = tempfile::tempdir().unwrap();
+    let output = tempfile::tempdir().unwrap();
+
+    fs_rebuild(&[
+        "split",
+        "--chunks",
+        "3",
+        "--input",
+        input_dir().to_str().unwrap(),
+        "--output",
+        chunks.path().to_str().unwrap(),
+    ]);
+    fs_rebuild(&[
+        "rebuild",
+        "--source",
+        &format!("file://{}", chunks.path().display()),
+        "--output",
+        output.path().to_str().unwrap(),
+    ]);
+
+    assert_same_tree(&input_dir(), output.path());
+}
+
+/// How `FlakyServer` misbehaves.
+#[derive(Clone, Copy, Debug)]
+enum Flaw {
+    /// Hangs up halfway through the first response for each file.
+    CutBody,
+    /// Like `CutBody`, and never honors a `Range` header.
+    IgnoreRange,
+    /// Answers the first request for each file with a 503.
+    Unavailable,
+}
+
+/// Serves the files of a directory like `fs-rebuild serve`, except that
+/// everything but the manifest fails the way `flaw` says.
+struct FlakyServer {
+    host: String,
+    ranges: Arc<AtomicUsize>,
+}
+
+impl FlakyServer {
+    fn start(dir: &Path, flaw: Flaw) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let host = format!("http://{}", listener.local_addr().unwrap());
+        let ranges = Arc::new(AtomicUsize::new(0));
+        let requests = Arc::new(Mutex::new(HashMap::<String, usize>::new()));
+
+        let (dir, served_ranges) = (dir.to_path_buf(), ranges.clone());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let (dir, ranges, requests) =
+                    (dir.clone(), served_ranges.clone(), requests.clone());
+                thread::spawn(move || {
+                    Self::respond(stream.unwrap(), &dir, flaw, &ranges, &requests)
+                });
+            }
+        });
+
+        Self { host, ranges }
+    }
+
+    fn respond(
+        mut stream: TcpStream,
+        dir: &Path,
+        flaw: Flaw,
+        ranges: &AtomicUsize,
+        requests: &Mutex<HashMap<String, usize>>,
+    ) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let name = line
+            .split(' ')
+            .nth(1)
+            .unwrap()
+            .trim_start_matches('/')
+            .to_string();
+
+        let mut range = None;
+        loop {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            if line.trim().is_empty() {
+                break;
+            }
+            let lower = line.to_ascii_lowercase();
+            if let Some(bytes) = lower.trim().strip_prefix("range: bytes=") {
+                let (start, end) = bytes.split_once('-').unwrap();
+                range = Some((start.parse::<usize>().unwrap(), end.parse::<usize>().ok()));
+            }
+        }
+
+        let data = match fs::read(dir.join(&name)) {
+            Ok(data) => data,
+            Err(_) => {
+                let _ = stream.write_all(b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n");
+                return;
+            }
+        };
+        let first = {
+            let mut requests = requests.lock().unwrap();
+            let count = requests.entry(name.clone()).or_default();
+            *count += 1;
+            *count == 1 && name != "manifest.json"
+        };
+
+        if first {
+            if let Flaw::Unavailable = flaw {
+                let _ = stream
+                    .write_all(b"HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\n\r\n");
+                return;
+            }
+        }
+
+        let (status, body) = match (range, flaw) {
+            (Some((start, end)), Flaw::CutBody | Flaw::Unavailable) => {
+                ranges.fetch_add(1, Ordering::Relaxed);
+                let end = end.map_or(data.len(), |end| end + 1).min(data.len());
+                let status = format!(
+                    "206 Partial Content\r\ncontent-range: bytes {}-{}/{}",
+                    start,
+                    end - 1,
+                    data.len()
+                );
+                (status, &data[start..end])
+            }
+            _ => ("200 OK".to_string(), &data[..]),
+        };
+        let header = format!(
+            "HTTP/1.1 {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+            status,
+            body.len()
+        );
+        let _ = stream.write_all(header.as_bytes());
+        let sent = if first { body.len() / 2 } else { body.len() };
+        let _ = stream.write_all(&body[..sent]);
+    }
+}
+
+#[test]
+fn rebuild_and_cat_retry_and_resume_a_flaky_server() {
+    let tar = tempfile::tempdir().unwrap();
+    let indexed = tempfile::tempdir().unwrap();
+    for (chunks, container) in [(&tar, "tar"), (&indexed, "indexed")] {
+        fs_rebuild(&[
+            "split",
+            "--chunks",
+            "3",
+            "--container",
+            container,
+            "--input",
+            input_dir().to_str().unwrap(),
+            "--output",
+            chunks.path().to_str().unwrap(),
+        ]);
+    }
+
+    let rebuild = |server: &FlakyServer, args: &[&str]| {
+        let output = tempfile::tempdir().unwrap();
+        let status = Command::new(env!("CARGO_BIN_EXE_fs-rebuild"))
+            .args(["rebuild", "--backoff-ms", "1", "--source", &server.host])
+            .args(args)
+            .arg("--output")
+            .arg(output.path())
+            .status()
+            .unwrap();
+        if status.success() {
+            assert_same_tree(&input_dir(), output.path());
+        }
+        status.success()
+    };
+
+    for flaw in [Flaw::CutBody, Flaw::IgnoreRange, Flaw::Unavailable] {
+        for chunks in [&tar, &indexed] {
+            let server = FlakyServer::start(chunks.path(), flaw);
+            assert!(rebuild(&server, &["--retries", "2"]), "{:?}", flaw);
+            if let Flaw::CutBody = flaw {
+                assert!(server.ranges.load(Ordering::Relaxed) > 0);
+            }
+        }
+
+        // A ranged read of the table of contents, then of the file
+        let server = FlakyServer::start(indexed.path(), flaw);
+        let cat = Command::new(env!("CARGO_BIN_EXE_fs-rebuild"))
+            .args(["cat", "--retries", "2", "--backoff-ms", "1"])
+            .args(["--source", &server.host, "b/b"])
+            .output()
+            .unwrap();
+        assert!(cat.status.success(), "{:?}", flaw);
+        assert_eq!(cat.stdout, fs::read(input_dir().join("b/b")).unwrap());
+    }
+
+    // Without retries, only a refetch round gets past the first failures
+    let server = FlakyServer::start(tar.path(), Flaw::Unavailable);
+    assert!(!rebuild(&server, &["--retries", "0"]));
+    let server = FlakyServer::start(tar.path(), Flaw::Unavailable);
+    assert!(rebuild(
+        &server,
+        &["--retries", "0", "--refetch-rounds", "1"]
+    ));
+
+    // Retries far past the point where the backoff doubling would overflow
+    let server = FlakyServer::start(tar.path(), Flaw::CutBody);
+    assert!(rebuild(&server, &["--retries", "100"]));
+}
+
+#[test]
+fn rebuild_writes_metrics_json() {
+    let chunks = tempfile::tempdir().unwrap();
+    let output = tempfile::tempdir().unwrap();
+    let metrics = chunks.path().join("metrics.json");
+
+    fs_rebuild(&[
+        "split",
+        "--chunks",
+        "3",
+        "--input",
+        input_dir().to_str().unwrap(),
+        "--output",
+        chunks.path().to_str().unwrap(),
+    ]);
+    fs_rebuild(&[
+        "rebuild",
+        "--source",
+        chunks.path().to_str().unwrap(),
+        "--output",
+        output.path().to_str().unwrap(),
+        "--metrics-json",
+        metrics.to_str().unwrap(),
+    ]);
+
+    let report: serde_json::Value = serde_json::from_slice(&fs::read(&metrics).unwrap()).unwrap();
+    let files = WalkDir::new(input_dir())
+        .into_iter()
+        .filter(|entry| entry.as_ref().unwrap().file_type().is_file())
+        .count();
+    assert_eq!(report["files"], files);
+    assert_eq!(report["failed_attempts"], 0);
+    assert_eq!(report["chunks"].as_array().unwrap().len(), 3);
+    assert!(report["bytes_out"].as_u64().unwrap() > report["bytes_in"].as_u64().unwrap());
+    for phase in ["manifest", "fetch", "restore"] {
+        assert!(report["phases_ms"][phase].is_number(), "no {} phase", phase);
+    }
+}
+
+#[test]
+fn rebuild_fsyncs_according_to_durability() {
+    let chunks = tempfile::tempdir().unwrap();
+    let parent = tempfile::tempdir().unwrap();
+    let output = parent.path().join("node_modules");
+    let metrics = parent.path().join("metrics.json");
+
+    fs_rebuild(&[
+        "split",
+        "--chunks",
+        "3",
+        "--input",
+        input_dir().to_str().unwrap(),
+        "--output",
+        chunks.path().to_str().unwrap(),
+    ]);
+
+    for (durability, per_file) in [
+        ("none", false),
+        ("file", true),
+        ("syncfs", false),
+        ("dir", true),
+    ] {
+        fs_rebuild(&[
+            "rebuild",
+            "--durability",
+            durability,
+            "--source",
+            chunks.path().to_str().unwrap(),
+            "--output",
+            output.to_str().unwrap(),
+            "--metrics-json",
+            metrics.to_str().unwrap(),
+        ]);
+        assert_same_tree(&input_dir(), &output);
+
+        let report: serde_json::Value =
+            serde_json::from_slice(&fs::read(&metrics).unwrap()).unwrap();
+        assert!(report["phases_ms"]["sync"].is_number());
+        for chunk in report["chunks"].as_array().unwrap() {
+            let fsync_ms = chunk["fsync_ms"].as_f64().unwrap();
+            assert_eq!(
+                fsync_ms > 0.0,
+                per_file,
+                "{} fsync {}",
+                durability,
+                fsync_ms
+            );
+        }
+    }
+}
+
+#[test]
+fn bench_reports_every_source_and_concurrency() {
+    let one = tempfile::tempdir().unwrap();
+    let three = tempfile::tempdir().unwrap();
+    let output = tempfile::tempdir().unwrap();
+
+    for (chunks, count) in [(&one, "1"), (&three, "3")] {
+        fs_rebuild(&[
+            "split",
+            "--chunks",
+            count,
+            "--input",
+            input_dir().to_str().unwrap(),
+            "--output",
+            chunks.path().to_str().unwrap(),
+        ]);
+    }
+
+    let bench = Command::new(env!("CARGO_BIN_EXE_fs-rebuild"))
+        .args(["bench", "--iterations", "3", "--concurrency", "1,4"])
+        .args(["--format", "json", "--source"])
+        .arg(one.path())
+        .arg("--source")
+        .arg(three.path())
+        .arg("--output")
+        .arg(output.path())
+        .output()
+        .unwrap();
+    assert!(bench.status.success());
+
+    let rows: Vec<serde_json::Value> = serde_json::from_slice(&bench.stdout).unwrap();
+    let configurations = rows
+        .iter()
+        .map(|row| {
+            (
+                row["chunks"].as_u64().unwrap(),
+                row["concurrency"].as_u64().unwrap(),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(configurations, vec![(1, 1), (1, 4), (3, 1), (3, 4)]);
+    for row in rows.iter() {
+        assert!(row["min_ms"].as_f64().unwrap() <= row["median_ms"].as_f64().unwrap());
+        assert!(row["median_ms"].as_f64().unwrap() <= row["p95_ms"].as_f64().unwrap());
+    }
+    assert_same_tree(&input_dir(), output.path());
+}
+
+/// Writes an executable script, a symlink to it and an empty directory, the
+/// entries that need more than their contents round-tripped.
+fn write_special_entries(input: &Path) {
+    let script = input.join("pkg/bin/cli");
+    fs::create_dir_all(script.parent().unwrap()).unwrap();
+    fs::write(&script, "#!/bin/sh\n").unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+    fs::create_dir_all(input.join(".bin")).unwrap();
+    symlink("../pkg/bin/cli", input.join(".bin/cli")).unwrap();
+    fs::create_dir_all(input.join("empty")).unwrap();
+}
+
+fn assert_special_entries(output: &Path) {
+    let meta = fs::metadata(output.join("pkg/bin/cli")).unwrap();
+    assert_eq!(meta.permissions().mode() & 0o777, 0o755);
+    assert_eq!(
+        fs::read_link(output.join(".bin/cli")).unwrap(),
+        Path::new("../pkg/bin/cli")
+    );
+    assert!(output.join("empty").is_dir());
+}
+
+/// Leaves a previous tree at `output` where the file at `path` was replaced
+/// by a symlink, which a rebuild in place mustn't write through.
+fn leave_symlink_in_place_of(output: &Path, path: &str) {
+    let path = output.join(path);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    symlink("/etc/passwd", path).unwrap();
+}
+
+#[test]
+fn rebuild_keeps_symlinks_empty_directories_and_modes() {
+    let input = tempfile::tempdir().unwrap();
+    let chunks = tempfile::tempdir().unwrap();
+    let output = tempfile::tempdir().unwrap();
+
+    write_special_entries(input.path());
+    split_and_rebuild(input.path(), chunks.path(), output.path());
+    assert_special_entries(output.path());
+}
+
+#[cfg(feature = "io-uring")]
+#[test]
+fn io_uring_writer_matches_tar() {
+    let input = tempfile::tempdir().unwrap();
+    let chunks = tempfile::tempdir().unwrap();
+    let output = tempfile::tempdir().unwrap();
+
+    write_special_entries(input.path());
+    fs::write(input.path().join("pkg/large.bin"), vec![7; 3 * 1024 * 1024]).unwrap();
+    for idx in 0..300 {
+        fs::write(
+            input.path().join(format!("pkg/{}.js", idx)),
+            idx.to_string(),
+        )
+        .unwrap();
+    }
+
+    fs_rebuild(&[
+        "split",
+        "--input",
+        input.path().to_str().unwrap(),
+        "--output",
+        chunks.path().to_str().unwrap(),
+    ]);
+    leave_symlink_in_place_of(output.path(), "pkg/0.js");
+    fs_rebuild(&[
+        "rebuild",
+        "--writer",
+        "io-uring",
+        "--in-place",
+        "--source",
+        chunks.path().to_str().unwrap(),
+        "--output",
+        output.path().to_str().unwrap(),
+    ]);
+
+    assert_same_tree(input.path(), output.path());
+    assert_special_entries(output.path());
+}
+
+#[test]
+fn pool_writer_matches_tar() {
+    let input = tempfile::tempdir().unwrap();
+    let chunks = tempfile::tempdir().unwrap();
+    let output = tempfile::tempdir().unwrap();
+
+    write_special_entries(input.path());
+    // Written in pieces by several workers
+    let large = (0..9 * 1024 * 1024 + 17)
+        .map(|idx| (idx % 251) as u8)
+        .collect::<Vec<_>>();
+    fs::write(input.path().join("pkg/large.bin"), large).unwrap();
+    for idx in 0..300 {
+        let dir = input.path().join(format!("pkg/{}", idx % 7));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(format!("{}.js", idx)), idx.to_string()).unwrap();
+    }
+
+    fs_rebuild(&[
+        "split",
+        "--input",
+        input.path().to_str().unwrap(),
+        "--output",
+        chunks.path().to_str().unwrap(),
+    ]);
+    leave_symlink_in_place_of(output.path(), "pkg/0/0.js");
+    fs_rebuild(&[
+        "rebuild",
+        "--writer",
+        "pool",
+        "--write-workers",
+        "3",
+        "--durability",
+        "file",
+        "--in-place",
+        "--source",
+        chunks.path().to_str().unwrap(),
+        "--output",
+        output.path().to_str().unwrap(),
+    ]);
+
+    assert_same_tree(input.path(), output.path());
+    assert_special_entries(output.path());
+}
+
+#[test]
+fn rebuild_reads_every_container_and_codec() {
+    let input = tempfile::tempdir().unwrap();
+
+    write_special_entries(input.path());
+    for idx in 0..20 {
+        fs::write(
+            input.path().join(format!("pkg/{}.js", idx)),
+            format!("module.exports = {};\n", idx),
+        )
+        .unwrap();
+    }
+
+    for container in ["tar", "indexed"] {
+        for (codec, extension) in [
+            ("zstd", "zst"),
+            ("lz4", "lz4"),
+            ("gzip", "gz"),
+            ("none", ""),
+        ] {
+            let chunks = tempfile::tempdir().unwrap();
+            let output = tempfile::tempdir().unwrap();
+            fs_rebuild(&[
+                "split",
+                "--chunks",
+                "2",
+                "--container",
+                container,
+                "--codec",
+                codec,
+                "--input",
+                input.path().to_str().unwrap(),
+                "--output",
+                chunks.path().to_str().unwrap(),
+            ]);
+            fs_rebuild(&[
+                "rebuild",
+                "--source",
+                chunks.path().to_str().unwrap(),
+                "--output",
+                output.path().to_str().unwrap(),
+            ]);
+
+            let expected = match (container, extension) {
+                ("indexed", _) => "1.idx".to_string(),
+                (_, "") => "1.tar".to_string(),
+                (_, extension) => format!("1.tar.{}", extension),
+            };
+            assert_eq!(chunk_names(chunks.path())[0], expected);
+            assert_same_tree(input.path(), output.path());
+            assert_special_entries(output.path());
+        }
+    }
+}
+
+#[test]
+fn rebuild_reads_every_zstd_option() {
+    let input = tempfile::tempdir().unwrap();
+    let dictionaries = tempfile::tempdir().unwrap();
+    for idx in 0..200 {
+        let dir = input.path().join(format!("pkg-{}", idx % 10));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join(format!("{}.json", idx)),
+            format!(
+                "{{\"name\": \"pkg-{}\", \"version\": \"1.{}.0\", \"main\": \"index.js\"}}\n",
+                idx % 10,
+                idx
+            ),
+        )
+        .unwrap();
+    }
+
+    let split = |chunks: &Path, extra: &[&str]| {
+        let mut args = vec![
+            "split",
+            "--chunks",
+            "3",
+            "--input",
+            input.path().to_str().unwrap(),
+            "--output",
+            chunks.to_str().unwrap(),
+        ];
+        args.extend_from_slice(extra);
+        fs_rebuild(&args);
+    };
+    let rebuild = |chunks: &Path, extra: &[&str]| {
+        let output = tempfile::tempdir().unwrap();
+        let status = Command::new(env!("CARGO_BIN_EXE_fs-rebuild"))
+            .args(["rebuild", "--source", chunks.to_str().unwrap(), "--output"])
+            .arg(output.path())
+            .args(extra)
+            .status()
+            .unwrap();
+        if status.success() {
+            assert_same_tree(input.path(), output.path());
+        }
+        status.success()
+    };
+    let compression = |chunks: &Path| {
+        let manifest: serde_json::Value =
+            serde_json::from_slice(&fs::read(chunks.join("manifest.json")).unwrap()).unwrap();
+        manifest["compression"].clone()
+    };
+
+    let trained = tempfile::tempdir().unwrap();
+    split(trained.path(), &["--train-dictionary", "4096"]);
+    assert!(rebuild(trained.path(), &[]));
+    let dictionary = dictionaries.path().join("trained.zdict");
+    fs::copy(trained.path().join("dictionary.zdict"), &dictionary).unwrap();
+
+    let cases: &[&[&str]] = &[
+        &["--long"],
+        &["--window-log", "24"],
+        &["--level", "19", "--workers", "2"],
+        &["--dictionary", dictionary.to_str().unwrap()],
+        &[
+            "--container",
+            "indexed",
+            "--dictionary",
+            dictionary.to_str().unwrap(),
+        ],
+    ];
+    for extra in cases.iter() {
+        let chunks = tempfile::tempdir().unwrap();
+        split(chunks.path(), extra);
+        assert!(rebuild(chunks.path(), &[]), "{:?}", extra);
+    }
+
+    // Rebuild refuses windows larger than it allows
+    let chunks = tempfile::tempdir().unwrap();
+    split(chunks.path(), &["--long"]);
+    assert_eq!(compression(chunks.path())["long_distance_matching"], true);
+    assert!(!rebuild(chunks.path(), &["--max-window-log", "26"]));
+    let chunks = tempfile::tempdir().unwrap();
+    split(chunks.path(), &["--window-log", "28"]);
+    assert_eq!(compression(chunks.path())["window_log"], 28);
+    assert!(!rebuild(chunks.path(), &[]));
+    assert!(rebuild(chunks.path(), &["--max-window-log", "28"]));
+
+    // An incremental split keeps the dictionary it trained the first time
+    let before = compression(trained.path())["dictionary"].clone();
+    let names = chunk_names(trained.path());
+    fs::write(input.path().join("pkg-3/3.json"), "{}").unwrap();
+    split(
+        trained.path(),
+        &["--incremental", "--train-dictionary", "4096"],
+    );
+    assert_eq!(compression(trained.path())["dictionary"], before);
+    let changed = names
+        .iter()
+        .zip(chunk_names(trained.path()).iter())
+        .filter(|(before, after)| before != after)
+        .count();
+    assert_eq!(changed, 1);
+    assert!(rebuild(trained.path(), &[]));
+}
+
+#[test]
+fn cat_reads_one_file_from_indexed_chunks() {
+    let indexed = tempfile::tempdir().unwrap();
+    let tar = tempfile::tempdir().unwrap();
+    for (chunks, container) in [(&indexed, "indexed"), (&tar, "tar")] {
+        fs_rebuild(&[
+            "split",
+            "--container",
+            container,
+            "--input",
+            input_dir().to_str().unwrap(),
+            "--output",
+            chunks.path().to_str().unwrap(),
+        ]);
+    }
+
+    // Over HTTP, where only the table of contents and the file are fetched
+    let server = Server::start(indexed.path());
+    let cat = Command::new(env!("CARGO_BIN_EXE_fs-rebuild"))
+        .args(["cat", "--source", &server.host, "b/b"])
+        .output()
+        .unwrap();
+    assert!(cat.status.success());
+    assert_eq!(cat.stdout, fs::read(input_dir().join("b/b")).unwrap());
+
+    let cat = |chunks: &Path, path: &str| {
+        Command::new(env!("CARGO_BIN_EXE_fs-rebuild"))
+            .args(["cat", "--source", chunks.to_str().unwrap(), path])
+            .output()
+            .unwrap()
+            .status
+            .success()
+    };
+    assert!(cat(indexed.path(), "a/b"));
+    assert!(!cat(indexed.path(), "missing"));
+    assert!(!cat(tar.path(), "b/b"));
+
+    // A size no file could have isn't allocated up front
+    let manifest = indexed.path().join("manifest.json");
+    let mut json: serde_json::Value =
+        serde_json::from_slice(&fs::read(&manifest).unwrap()).unwrap();
+    let file = &mut json["chunks"][0]["files"][0];
+    file["size"] = serde_json::json!(u64::MAX / 2);
+    let path = file["path"].as_str().unwrap().to_string();
+    fs::write(&manifest, json.to_string()).unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_fs-rebuild"))
+        .args(["cat", "--source", indexed.path().to_str().unwrap(), &path])
+        .output()
+        .unwrap()
+        .status;
+    assert!(status.code().is_some(), "cat was killed by {:?}", status);
+    let output = tempfile::tempdir().unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_fs-rebuild"))
+        .args(["rebuild", "--source", indexed.path().to_str().unwrap()])
+        .arg("--output")
+        .arg(output.path())
+        .status()
+        .unwrap();
+    assert!(!status.success());
+}
+
+#[test]
+fn parallel_split_writes_the_same_chunks_as_sequential() {
+    let input = tempfile::tempdir().unwrap();
+    for idx in 0..100 {
+        let dir = input.path().join(format!("pkg-{}", idx % 9));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(format!("{}.js", idx)), idx.to_string().repeat(idx)).unwrap();
+    }
+
+    let split = |jobs: &str, container: &str| {
+        let chunks = tempfile::tempdir().unwrap();
+        fs_rebuild(&[
+            "split",
+            "--chunks",
+            "8",
+            "--jobs",
+            jobs,
+            "--container",
+            container,
+            "--input",
+            input.path().to_str().unwrap(),
+            "--output",
+            chunks.path().to_str().unwrap(),
+        ]);
+        fs::read(chunks.path().join("manifest.json")).unwrap()
+    };
+
+    for container in ["tar", "indexed"] {
+        let sequential = split("1", container);
+        assert_eq!(split("4", container), sequential);
+        assert_eq!(split("16", container), sequential);
+    }
+}
+
+#[test]
+fn split_keeps_packages_in_one_chunk() {
+    let input = tempfile::tempdir().unwrap();
+    let chunks = tempfile::tempdir().unwrap();
+
+    let packages = ["left-pad", "@types/node", "react"];
+    for package in packages.iter() {
+        let dir = input.path().join(package);
+        fs::create_dir_all(dir.join("node_modules/dep")).unwrap();
+        fs::write(dir.join("package.json"), "{}").unwrap();
+        fs::write(dir.join("index.js"), "module.exports = 1;\n").unwrap();
+        fs::write(dir.join("node_modules/dep/index.js"), "1").unwrap();
+    }
+
+    fs_rebuild(&[
+        "split",
+        "--chunks",
+        "2",
+        "--group",
+        "package",
+        "--input",
+        input.path().to_str().unwrap(),
+        "--output",
+        chunks.path().to_str().unwrap(),
+    ]);
+
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&fs::read(chunks.path().join("manifest.json")).unwrap()).unwrap();
+    for package in packages.iter() {
+        let holding = manifest["chunks"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|chunk| {
+                chunk["files"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .any(|file| file["path"].as_str().unwrap().starts_with(package))
+            })
+            .count();
+        assert_eq!(holding, 1, "{} is spread over {} chunks", package, holding);
+    }
+}
+
+fn chunk_names(chunks: &Path) -> Vec<String> {
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&fs::read(chunks.join("manifest.json")).unwrap()).unwrap();
+    manifest["chunks"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|chunk| chunk["name"].as_str().unwrap().to_string())
+        .collect()
+}
+
+#[test]
+fn incremental_split_only_rewrites_changed_chunks() {
+    let input = tempfile::tempdir().unwrap();
+    let chunks = tempfile::tempdir().unwrap();
+    let output = tempfile::tempdir().unwrap();
+
+    for idx in 0..12 {
+        fs::write(input.path().join(format!("{}.js", idx)), idx.to_string()).unwrap();
+    }
+    let split = |extra: &[&str]| {
+        let mut args = vec![
+            "split",
+            "--chunks",
+            "4",
+            "--input",
+            input.path().to_str().unwrap(),
+            "--output",
+            chunks.path().to_str().unwrap(),
+        ];
+        args.extend_from_slice(extra);
+        fs_rebuild(&args);
+    };
+
+    let changed = |before: &[String], after: &[String]| {
+        before
+            .iter()
+            .zip(after.iter())
+            .filter(|(before, after)| before != after)
+            .count()
+    };
+
+    split(&[]);
+    let before = chunk_names(chunks.path());
+
+    fs::write(input.path().join("3.js"), "changed").unwrap();
+    split(&["--incremental"]);
+    let after = chunk_names(chunks.path());
+    assert_eq!(changed(&before, &after), 1);
+    for name in before.iter().chain(after.iter()) {
+        assert!(chunks.path().join(name).is_file(), "{} is missing", name);
+    }
+
+    // A new mode alone changes the chunk's tar headers
+    let script = input.path().join("5.js");
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+    split(&["--incremental"]);
+    assert_eq!(changed(&after, &chunk_names(chunks.path())), 1);
+
+    fs_rebuild(&[
+        "rebuild",
+        "--source",
+        chunks.path().to_str().unwrap(),
+        "--output",
+        output.path().to_str().unwrap(),
+    ]);
+    assert_same_tree(input.path(), output.path());
+    let meta = fs::metadata(output.path().join("5.js")).unwrap();
+    assert_eq!(meta.permissions().mode() & 0o777, 0o755);
+}
+
+#[test]
+fn delta_rebuild_updates_and_prunes_the_output() {
+    let input = tempfile::tempdir().unwrap();
+    let chunks = tempfile::tempdir().unwrap();
+    let output = tempfile::tempdir().unwrap();
+
+    for package in ["a", "b", "c"].iter() {
+        let dir = input.path().join(package);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("index.js"), package).unwrap();
+    }
+    let split_and_delta = || {
+        fs_rebuild(&[
+            "split",
+            "--incremental",
+            "--input",
+            input.path().to_str().unwrap(),
+            "--output",
+            chunks.path().to_str().unwrap(),
+        ]);
+        fs_rebuild(&[
+            "rebuild",
+            "--delta",
+            "--source",
+            chunks.path().to_str().unwrap(),
+            "--output",
+            output.path().to_str().unwrap(),
+        ]);
+    };
+
+    split_and_delta();
+    assert_same_tree(input.path(), output.path());
+
+    fs::write(input.path().join("a/index.js"), "changed").unwrap();
+    fs::remove_dir_all(input.path().join("b")).unwrap();
+    fs::write(input.path().join("c/new.js"), "new").unwrap();
+
+    split_and_delta();
+    assert_same_tree(input.path(), output.path());
+    assert!(!output.path().join("b").exists());
+
+    // A rebuild without --delta in between leaves nothing to skip
+    let v2 = tempfile::tempdir().unwrap();
+    fs::write(input.path().join("a/index.js"), "v2").unwrap();
+    fs_rebuild(&[
+        "split",
+        "--input",
+        input.path().to_str().unwrap(),
+        "--output",
+        v2.path().to_str().unwrap(),
+    ]);
+    fs_rebuild(&[
+        "rebuild",
+        "--in-place",
+        "--source",
+        v2.path().to_str().unwrap(),
+        "--output",
+        output.path().to_str().unwrap(),
+    ]);
+    fs::write(input.path().join("a/index.js"), "changed").unwrap();
+    fs_rebuild(&[
+        "rebuild",
+        "--delta",
+        "--source",
+        chunks.path().to_str().unwrap(),
+        "--output",
+        output.path().to_str().unwrap(),
+    ]);
+    assert_same_tree(input.path(), output.path());
+}
+
+#[test]
+fn store_shares_blobs_between_versions() {
+    let input = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    let cache = tempfile::tempdir().unwrap();
+
+    fs::create_dir_all(input.path().join("pkg/lib")).unwrap();
+    fs::write(input.path().join("pkg/index.js"), "index").unwrap();
+    fs::write(input.path().join("pkg/lib/copy.js"), "index").unwrap();
+    symlink("index.js", input.path().join("pkg/main.js")).unwrap();
+
+    let version = |tag: &str, extra: &[&str]| {
+        let mut args = vec![
+            "split",
+            "--store",
+            "--tag",
+            tag,
+            "--input",
+            input.path().to_str().unwrap(),
+            "--output",
+            store.path().to_str().unwrap(),
+        ];
+        args.extend_from_slice(extra);
+        fs_rebuild(&args);
+
+        let output = tempfile::tempdir().unwrap();
+        fs_rebuild(&[
+            "rebuild",
+            "--index",
+            tag,
+            "--cache",
+            cache.path().to_str().unwrap(),
+            "--source",
+            store.path().to_str().unwrap(),
+            "--output",
+            output.path().to_str().unwrap(),
+        ]);
+        assert_same_tree(input.path(), output.path());
+        assert_eq!(
+            fs::read_link(output.path().join("pkg/main.js")).unwrap(),
+            Path::new("index.js")
+        );
+    };
+    let blobs = |root: &Path| {
+        WalkDir::new(root.join("blobs"))
+            .into_iter()
+            .filter(|entry| entry.as_ref().unwrap().file_type().is_file())
+            .count()
+    };
+
+    version("v1", &[]);
+    assert_eq!(blobs(store.path()), 1);
+
+    fs::write(input.path().join("pkg/new.js"), "new").unwrap();
+    version("v2", &[]);
+    assert_eq!(blobs(store.path()), 2);
+    assert_eq!(blobs(cache.path()), 2);
+
+    // Blobs another codec or window can't decode aren't shared
+    version("v3", &["--codec", "gzip"]);
+    assert_eq!(blobs(store.path()), 4);
+    version("v4", &["--long"]);
+    assert_eq!(blobs(store.path()), 6);
+    version("v5", &["--long", "--level", "19"]);
+    assert_eq!(blobs(store.path()), 6);
+    version("v2", &[]);
+    assert_eq!(blobs(cache.path()), 6);
+
+    // Cached blobs too garbled to decode are fetched again
+    for entry in WalkDir::new(cache.path()) {
+        let entry = entry.unwrap();
+        if entry.file_type().is_file() {
+            fs::write(entry.path(), "garbage").unwrap();
+        }
+    }
+    let output = tempfile::tempdir().unwrap();
+    fs_rebuild(&[
+        "rebuild",
+        "--index",
+        "v2",
+        "--refetch-rounds",
+        "1",
+        "--cache",
+        cache.path().to_str().unwrap(),
+        "--source",
+        store.path().to_str().unwrap(),
+        "--output",
+        output.path().to_str().unwrap(),
+    ]);
+    assert_same_tree(input.path(), output.path());
+    assert_eq!(blobs(cache.path()), 6);
+}
+
+#[test]
+fn rebuild_never_writes_through_symlinks_left_in_the_output() {
+    let input = tempfile::tempdir().unwrap();
+    let chunks = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    let outside = tempfile::tempdir().unwrap();
+
+    fs::create_dir(input.path().join("y")).unwrap();
+    fs::write(input.path().join("y/pwned"), "pwned").unwrap();
+    fs_rebuild(&[
+        "split",
+        "--input",
+        input.path().to_str().unwrap(),
+        "--output",
+        chunks.path().to_str().unwrap(),
+    ]);
+    fs_rebuild(&[
+        "split",
+        "--store",
+        "--tag",
+        "v1",
+        "--input",
+        input.path().to_str().unwrap(),
+        "--output",
+        store.path().to_str().unwrap(),
+    ]);
+
+    let rebuild = |args: &[&str]| {
+        let output = tempfile::tempdir().unwrap();
+        symlink(outside.path(), output.path().join("y")).unwrap();
+        let status = Command::new(env!("CARGO_BIN_EXE_fs-rebuild"))
+            .args(["rebuild", "--in-place", "--output"])
+            .arg(output.path())
+            .args(args)
+            .status()
+            .unwrap();
+        assert_eq!(fs::read_dir(outside.path()).unwrap().count(), 0);
+        (status.success(), output)
+    };
+
+    // Directories in the manifest or index replace the symlink
+    let store_args = ["--index", "v1", "--source", store.path().to_str().unwrap()];
+    for args in [
+        &["--source", chunks.path().to_str().unwrap()][..],
+        &store_args,
+    ] {
+        let (success, output) = rebuild(args);
+        assert!(success, "{:?}", args);
+        assert!(fs::symlink_metadata(output.path().join("y"))
+            .unwrap()
+            .is_dir());
+        assert_same_tree(input.path(), output.path());
+    }
+
+    // Without the directory, there's nothing to replace it with
+    let index = store.path().join("indexes/v1.json");
+    let mut json: serde_json::Value = serde_json::from_slice(&fs::read(&index).unwrap()).unwrap();
+    json["directories"] = serde_json::json!([]);
+    fs::write(&index, json.to_string()).unwrap();
+    assert!(!rebuild(&store_args).0);
+}
+
+#[test]
+fn failed_rebuild_leaves_the_output_untouched() {
+    let chunks = tempfile::tempdir().unwrap();
+    let parent = tempfile::tempdir().unwrap();
+    let output = parent.path().join("node_modules");
+
+    fs::create_dir_all(&output).unwrap();
+    fs::write(output.join("previous.js"), "previous").unwrap();
+
+    fs_rebuild(&[
+        "split",
+        "--input",
+        input_dir().to_str().unwrap(),
+        "--output",
+        chunks.path().to_str().unwrap(),
+    ]);
+    fs::write(chunks.path().join("1.tar.zst"), "corrupt").unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_fs-rebuild"))
+        .args(["rebuild", "--source"])
+        .arg(chunks.path())
+        .arg("--output")
+        .arg(&output)
+        .status()
+        .unwrap();
+    assert!(!status.success());
+
+    assert_eq!(fs::read(output.join("previous.js")).unwrap(), b"previous");
+    assert_eq!(fs::read_dir(parent.path()).unwrap().count(), 1);
+
+    fs_rebuild(&[
+        "split",
+        "--input",
+        input_dir().to_str().unwrap(),
+        "--output",
+        chunks.path().to_str().unwrap(),
+    ]);
+    fs_rebuild(&[
+        "rebuild",
+        "--source",
+        chunks.path().to_str().unwrap(),
+        "--output",
+        output.to_str().unwrap(),
+    ]);
+    assert_same_tree(&input_dir(), &output);
+    assert_eq!(fs::read_dir(parent.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn rebuild_only_accepts_manifests_signed_by_the_public_key() {
+    let keys = tempfile::tempdir().unwrap();
+    let chunks = tempfile::tempdir().unwrap();
+    let output = tempfile::tempdir().unwrap();
+    let secret_key = keys.path().join("secret");
+    let public_key = keys.path().join("public");
+    let other_secret_key = keys.path().join("other-secret");
+    let other_public_key = keys.path().join("other-public");
+
+    fs_rebuild(&[
+        "keygen",
+        "--secret-key",
+        secret_key.to_str().unwrap(),
+        "--public-key",
+        public_key.to_str().unwrap(),
+    ]);
+    fs_rebuild(&[
+        "keygen",
+        "--secret-key",
+        other_secret_key.to_str().unwrap(),
+        "--public-key",
+        other_public_key.to_str().unwrap(),
+    ]);
+    let meta = fs::metadata(&secret_key).unwrap();
+    assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+    fs_rebuild(&[
+        "split",
+        "--input",
+        input_dir().to_str().unwrap(),
+        "--output",
+        chunks.path().to_str().unwrap(),
+        "--signing-key",
+        secret_key.to_str().unwrap(),
+    ]);
+
+    let rebuild = |public_key: &Path| {
+        Command::new(env!("CARGO_BIN_EXE_fs-rebuild"))
+            .args(["rebuild", "--source"])
+            .arg(chunks.path())
+            .arg("--output")
+            .arg(output.path())
+            .arg("--public-key")
+            .arg(public_key)
+            .status()
+            .unwrap()
+            .success()
+    };
+
+    assert!(rebuild(&public_key));
+    assert_same_tree(&input_dir(), output.path());
+    assert!(!rebuild(&other_public_key));
+
+    let manifest = chunks.path().join("manifest.json");
+    let mut tampered = fs::read(&manifest).unwrap();
+    tampered.push(b'\n');
+    fs::write(&manifest, tampered).unwrap();
+    assert!(!rebuild(&public_key));
+
+    fs::remove_file(chunks.path().join("manifest.json.sig")).unwrap();
+    assert!(!rebuild(&public_key));
+}
+
+/// Writes a single chunk holding `entries` and a manifest for it that lists
+/// no files, like a compromised server could.
+fn write_malicious_chunk(dir: &Path, entries: &[(EntryType, &[u8], &str)]) {
+    let mut archive = tar::Builder::new(vec![]);
+    for (kind, name, target) in entries.iter() {
+        let mut header = Header::new_gnu();
+        header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name);
+        header.set_entry_type(*kind);
+        header.set_mode(0o644);
+        header.set_size(0);
+        if !target.is_empty() {
+            header.set_link_name(target).unwrap();
+        }
+        header.set_cksum();
+        archive.append(&header, &[][..]).unwrap();
+    }
+
+    let chunk = zstd::encode_all(&archive.into_inner().unwrap()[..], 0).unwrap();
+    fs::write(dir.join("1.tar.zst"), &chunk).unwrap();
+
+    let manifest = serde_json::json!({
+        "chunks": [{
+            "name": "1.tar.zst",
+            "size": chunk.len(),
+            "file_count": 0,
+            "sha256": format!("{:x}", Sha256::digest(&chunk)),
+            "files": [],
+        }],
+    });
+    fs::write(dir.join("manifest.json"), manifest.to_string()).unwrap();
+}
+
+#[test]
+fn rebuild_refuses_entries_outside_the_output() {
+    let cases: &[&[(EntryType, &[u8], &str)]] = &[
+        &[(EntryType::Regular, b"../escape", "")],
+        &[(EntryType::Symlink, b"escape", "../../escape")],
+        &[
+            (EntryType::Symlink, b"up", ".."),
+            (EntryType::Regular, b"up/escape", ""),
+        ],
+        // Each link stays inside on its own, not once one goes through the other
+        &[
+            (EntryType::Symlink, b"x/l", ".."),
+            (EntryType::Symlink, b"y", "x/l/.."),
+            (EntryType::Regular, b"y/escape", ""),
+        ],
+        &[
+            (EntryType::Symlink, b"y", "x/l/.."),
+            (EntryType::Symlink, b"x/l", ".."),
+            (EntryType::Regular, b"y/escape", ""),
+        ],
+        &[(EntryType::Link, b"escape", "/etc/passwd")],
+    ];
+
+    for entries in cases.iter() {
+        let chunks = tempfile::tempdir().unwrap();
+        let parent = tempfile::tempdir().unwrap();
+        let output = parent.path().join("output");
+        fs::create_dir(&output).unwrap();
+        write_malicious_chunk(chunks.path(), entries);
+
+        let status = Command::new(env!("CARGO_BIN_EXE_fs-rebuild"))
+            .args(["rebuild", "--in-place", "--source"])
+            .arg(chunks.path())
+            .arg("--output")
+            .arg(&output)
+            .status()
+            .unwrap();
+        assert!(!status.success(), "{:?} was unpacked", entries);
+        assert!(!parent.path().join("escape").exists());
+        for entry in fs::read_dir(&output).into_iter().flatten() {
+            if let Ok(resolved) = entry.unwrap().path().canonicalize() {
+                assert!(resolved.starts_with(output.canonicalize().unwrap()));
+            }
+        }
+    }
+}
+
+#[test]
+fn serve_supports_ranges_and_etags() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("1.tar.zst"), "0123456789").unwrap();
+
+    let server = Server::start(dir.path());
+    let url = format!("{}/1.tar.zst", server.host);
+    let client = Client::new();
+
+    let resp = client.get(&url).header(RANGE, "bytes=4-").send().unwrap();
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    let etag = resp.headers()[ETAG].clone();
+    assert_eq!(resp.text().unwrap(), "456789");
+
+    let resp = client.get(&url).header(IF_NONE_MATCH, etag).send().unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+
+    let resp = client
+        .get(format!("{}/2.tar.zst", server.host))
+        .send()
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
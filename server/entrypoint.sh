@@ -2,12 +2,42 @@
 
 set -euo pipefail
 
+readonly INPUT_DIR="/mnt/data"
+readonly OUTPUT_DIR="${HOME}/output"
+
+readonly CHUNK_COUNT=8
+# Also split into chunks-N/ for each of these, for fs-rebuild bench to compare
+readonly BENCH_CHUNK_COUNTS=(1 3 5 8 16)
+# And into formats/<container>-<codec>/ for each of these
+readonly BENCH_FORMATS=(tar-zstd tar-lz4 tar-gzip tar-none indexed-zstd indexed-lz4)
+
 log() {
     echo "$(date +"%H:%M:%S") - $(printf '%s' "$@")" 1>&2
 }
 
+split_input() {
+    log "split input directory ${INPUT_DIR} into ${CHUNK_COUNT} chunks"
+
+    rm -rf "${OUTPUT_DIR}"
+    mkdir "${OUTPUT_DIR}"
+
+    "${HOME}/fs-rebuild" split --chunks "${CHUNK_COUNT}" --input "${INPUT_DIR}" --output "${OUTPUT_DIR}"
+
+    for count in "${BENCH_CHUNK_COUNTS[@]}"; do
+        log "split input directory ${INPUT_DIR} into ${count} chunks for bench"
+        "${HOME}/fs-rebuild" split --chunks "${count}" --input "${INPUT_DIR}" --output "${OUTPUT_DIR}/chunks-${count}"
+    done
+
+    for format in "${BENCH_FORMATS[@]}"; do
+        log "split input directory ${INPUT_DIR} as ${format} for bench"
+        "${HOME}/fs-rebuild" split --chunks "${CHUNK_COUNT}" \
+            --container "${format%%-*}" --codec "${format#*-}" \
+            --input "${INPUT_DIR}" --output "${OUTPUT_DIR}/formats/${format}"
+    done
+}
+
 main() {
-    "${HOME}/split.sh"
+    split_input
 
     log "start nginx"
     nginx